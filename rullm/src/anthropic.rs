@@ -0,0 +1,705 @@
+use std::time::Duration;
+
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionMessageToolCallChunk,
+    ChatCompletionRequestAssistantMessageContent, ChatCompletionRequestAssistantMessageContentPart,
+    ChatCompletionRequestDeveloperMessageContent, ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessageContent, ChatCompletionRequestSystemMessageContentPart,
+    ChatCompletionRequestToolMessageContent, ChatCompletionRequestToolMessageContentPart,
+    ChatCompletionRequestUserMessageContent, ChatCompletionRequestUserMessageContentPart,
+    ChatCompletionTool, ChatCompletionToolType, CompletionUsage, FunctionCall, FunctionCallStream,
+};
+use async_trait::async_trait;
+use reqwest::RequestBuilder;
+use reqwest_eventsource::{EventSource, retry::Never};
+use rmcp::serde_json::{self, Value, json};
+use serde::Deserialize;
+
+use crate::{
+    conf::{LLMConfig, RetryConfig, Sampling},
+    openai::StreamedReply,
+    provider::{Provider, Reply, next_event},
+    retry::{Failure, retry},
+};
+
+const API_VERSION: &str = "2023-06-01";
+// The API insists on a limit for the length of the answer, this one is used unless
+// `max_tokens` is set
+const DEFAULT_MAX_TOKENS: u32 = 4096;
+
+// The Messages API of Anthropic
+pub struct AnthropicClient {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: String,
+    model: String,
+    tools: Vec<ChatCompletionTool>,
+    stream: bool,
+    retry: RetryConfig,
+    timeout: Duration,
+    sampling: Sampling,
+}
+
+impl AnthropicClient {
+    pub fn build(conf: &LLMConfig, tools: Vec<ChatCompletionTool>) -> AnthropicClient {
+        let base_url = conf
+            .base_url
+            .clone()
+            .unwrap_or(String::from("https://api.anthropic.com/v1"));
+        let model = conf
+            .model
+            .clone()
+            .unwrap_or(String::from("claude-sonnet-4-5"));
+        AnthropicClient {
+            http: reqwest::Client::new(),
+            base_url,
+            api_key: conf.api_key.clone(),
+            model,
+            tools,
+            stream: conf.stream.unwrap_or(true),
+            retry: conf.retry.clone(),
+            timeout: Duration::from_secs(conf.timeout_secs.unwrap_or(120)),
+            sampling: conf.sampling.clone(),
+        }
+    }
+
+    fn request(
+        &self,
+        messages: &[ChatCompletionRequestMessage],
+        tools: bool,
+        stream: bool,
+        sampling: &Sampling,
+    ) -> Value {
+        let (system, messages) = convert_messages(messages);
+        let mut request = json!({
+            "model": self.model,
+            "max_tokens": sampling.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            "messages": messages,
+        });
+        // There is no seed, and the thinking of the models would have to be kept in the history
+        // for `reasoning_effort`
+        if let Some(temperature) = sampling.temperature {
+            request["temperature"] = json!(temperature);
+        }
+        if let Some(top_p) = sampling.top_p {
+            request["top_p"] = json!(top_p);
+        }
+        if let Some(stop) = &sampling.stop {
+            request["stop_sequences"] = json!(stop);
+        }
+        if !system.is_empty() {
+            request["system"] = Value::String(system);
+        }
+        if tools && !self.tools.is_empty() {
+            request["tools"] = self.tools.iter().map(convert_tool).collect();
+            if let Some(choice) = tool_choice(sampling) {
+                request["tool_choice"] = choice;
+            }
+        }
+        if stream {
+            request["stream"] = Value::Bool(true);
+        }
+        request
+    }
+
+    async fn create(&self, request: &Value) -> anyhow::Result<Reply> {
+        retry(&self.retry, async || self.create_once(request).await).await
+    }
+
+    async fn create_once(&self, request: &Value) -> Result<Reply, Failure> {
+        let response = self
+            .post(request)
+            .timeout(self.timeout)
+            .send()
+            .await
+            .map_err(Failure::from_reqwest)?;
+        if !response.status().is_success() {
+            return Err(Failure::from_response(response).await);
+        }
+        let response: Response = response.json().await?;
+        Ok(response.into())
+    }
+
+    async fn chat_stream(
+        &self,
+        request: &Value,
+        on_token: &mut dyn FnMut(&str),
+    ) -> Result<Reply, Failure> {
+        let mut events = EventSource::new(self.post(request))?;
+        events.set_retry_policy(Box::new(Never));
+        let mut reply = StreamedReply::default();
+        let mut usage = Usage::default();
+        let mut received = false;
+        while let Some(data) = next_event(&mut events, self.timeout, received).await? {
+            match serde_json::from_str::<StreamEvent>(&data)? {
+                StreamEvent::MessageStart { message } => usage = message.usage,
+                StreamEvent::ContentBlockStart {
+                    index,
+                    content_block: ContentBlock::ToolUse { id, name, .. },
+                } => reply.push_tool_call(tool_call_chunk(index, Some(id), Some(name), "")),
+                StreamEvent::ContentBlockStart { .. } => {}
+                StreamEvent::ContentBlockDelta { index, delta } => match delta {
+                    Delta::Text { text } => {
+                        received = true;
+                        on_token(&text);
+                        reply.content.push_str(&text);
+                    }
+                    Delta::InputJson { partial_json } => {
+                        reply.push_tool_call(tool_call_chunk(index, None, None, &partial_json))
+                    }
+                    Delta::Other => {}
+                },
+                StreamEvent::MessageDelta { usage: delta } => {
+                    usage.output_tokens = delta.output_tokens
+                }
+                StreamEvent::MessageStop => break,
+                StreamEvent::Error { error } => {
+                    let error = anyhow::anyhow!("{}: {}", error.r#type, error.message);
+                    return Err(match received {
+                        true => Failure::Permanent(error),
+                        false => Failure::Transient {
+                            error,
+                            retry_after: None,
+                        },
+                    });
+                }
+                StreamEvent::Other => {}
+            }
+        }
+        events.close();
+        reply.usage = Some(usage.into());
+        let mut reply = Reply::from(reply);
+        // A tool without parameters gets no pieces of input at all
+        for call in reply.tool_calls.iter_mut() {
+            if call.function.arguments.is_empty() {
+                call.function.arguments = String::from("{}");
+            }
+        }
+        Ok(reply)
+    }
+
+    fn post(&self, request: &Value) -> RequestBuilder {
+        self.http
+            .post(format!("{}/messages", self.base_url))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", API_VERSION)
+            .json(request)
+    }
+}
+
+#[async_trait(?Send)]
+impl Provider for AnthropicClient {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn set_model(&mut self, model: &str) {
+        self.model = String::from(model);
+    }
+
+    fn tools(&self) -> &[ChatCompletionTool] {
+        &self.tools
+    }
+
+    fn sampling(&self) -> &Sampling {
+        &self.sampling
+    }
+
+    async fn chat(
+        &self,
+        messages: &[ChatCompletionRequestMessage],
+        overrides: &Sampling,
+        on_token: &mut dyn for<'t> FnMut(&'t str),
+    ) -> anyhow::Result<Reply> {
+        let sampling = overrides.or(&self.sampling);
+        if !self.stream {
+            let reply = self
+                .create(&self.request(messages, true, false, &sampling))
+                .await?;
+            on_token(&reply.content);
+            return Ok(reply);
+        }
+        let request = self.request(messages, true, true, &sampling);
+        retry(&self.retry, async || {
+            self.chat_stream(&request, on_token).await
+        })
+        .await
+    }
+
+    async fn complete(&self, messages: &[ChatCompletionRequestMessage]) -> anyhow::Result<Reply> {
+        self.create(&self.request(messages, false, false, &self.sampling))
+            .await
+    }
+}
+
+#[derive(Deserialize)]
+struct Response {
+    content: Vec<ContentBlock>,
+    usage: Usage,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    // E.g. thinking, which isn't shown
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Default)]
+struct Usage {
+    #[serde(default)]
+    input_tokens: u32,
+    #[serde(default)]
+    output_tokens: u32,
+}
+
+#[derive(Deserialize)]
+struct MessageStart {
+    usage: Usage,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    r#type: String,
+    message: String,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamEvent {
+    MessageStart {
+        message: MessageStart,
+    },
+    ContentBlockStart {
+        index: u32,
+        content_block: ContentBlock,
+    },
+    ContentBlockDelta {
+        index: u32,
+        delta: Delta,
+    },
+    // The usage here is the total so far
+    MessageDelta {
+        usage: Usage,
+    },
+    MessageStop,
+    Error {
+        error: ApiError,
+    },
+    // Pings and the ends of the content blocks
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+enum Delta {
+    #[serde(rename = "text_delta")]
+    Text { text: String },
+    #[serde(rename = "input_json_delta")]
+    InputJson { partial_json: String },
+    #[serde(other)]
+    Other,
+}
+
+impl From<Usage> for CompletionUsage {
+    fn from(usage: Usage) -> Self {
+        CompletionUsage {
+            prompt_tokens: usage.input_tokens,
+            completion_tokens: usage.output_tokens,
+            total_tokens: usage.input_tokens + usage.output_tokens,
+            prompt_tokens_details: None,
+            completion_tokens_details: None,
+        }
+    }
+}
+
+impl From<Response> for Reply {
+    fn from(response: Response) -> Self {
+        let mut reply = Reply {
+            usage: Some(response.usage.into()),
+            ..Reply::default()
+        };
+        for block in response.content {
+            match block {
+                ContentBlock::Text { text } => reply.content.push_str(&text),
+                ContentBlock::ToolUse { id, name, input } => {
+                    reply.tool_calls.push(ChatCompletionMessageToolCall {
+                        id,
+                        r#type: ChatCompletionToolType::Function,
+                        function: FunctionCall {
+                            name,
+                            arguments: input.to_string(),
+                        },
+                    })
+                }
+                ContentBlock::Other => {}
+            }
+        }
+        reply
+    }
+}
+
+// The pieces of a streamed tool call are put together the same way as OpenAI's
+fn tool_call_chunk(
+    index: u32,
+    id: Option<String>,
+    name: Option<String>,
+    arguments: &str,
+) -> ChatCompletionMessageToolCallChunk {
+    ChatCompletionMessageToolCallChunk {
+        index,
+        r#type: id.as_ref().map(|_| ChatCompletionToolType::Function),
+        id,
+        function: Some(FunctionCallStream {
+            name,
+            arguments: Some(String::from(arguments)),
+        }),
+    }
+}
+
+// Parallel calls are turned off as a part of the choice
+fn tool_choice(sampling: &Sampling) -> Option<Value> {
+    let mut choice = match sampling.tool_choice.as_deref() {
+        None if sampling.parallel_tool_calls == Some(false) => json!({"type": "auto"}),
+        None => return None,
+        Some("none") => return Some(json!({"type": "none"})),
+        Some("auto") => json!({"type": "auto"}),
+        Some("required") => json!({"type": "any"}),
+        Some(name) => json!({"type": "tool", "name": name}),
+    };
+    if sampling.parallel_tool_calls == Some(false) {
+        choice["disable_parallel_tool_use"] = Value::Bool(true);
+    }
+    Some(choice)
+}
+
+fn convert_tool(tool: &ChatCompletionTool) -> Value {
+    let schema = tool
+        .function
+        .parameters
+        .clone()
+        .unwrap_or(json!({"type": "object", "properties": {}}));
+    json!({
+        "name": tool.function.name,
+        "description": tool.function.description.clone().unwrap_or_default(),
+        "input_schema": schema,
+    })
+}
+
+// The system prompt is sent on its own and the tool results are sent by the user. The roles of
+// the messages have to alternate, so consecutive messages of the same role are merged. That
+// also keeps the results of parallel tool calls together, as the API wants them
+fn convert_messages(messages: &[ChatCompletionRequestMessage]) -> (String, Vec<Value>) {
+    let mut system: Vec<String> = vec![];
+    let mut converted: Vec<(&str, Vec<Value>)> = vec![];
+    for message in messages {
+        let (role, blocks) = match message {
+            ChatCompletionRequestMessage::System(x) => {
+                system.push(match &x.content {
+                    ChatCompletionRequestSystemMessageContent::Text(text) => text.clone(),
+                    ChatCompletionRequestSystemMessageContent::Array(parts) => parts
+                        .iter()
+                        .map(|ChatCompletionRequestSystemMessageContentPart::Text(x)| {
+                            x.text.as_str()
+                        })
+                        .collect(),
+                });
+                continue;
+            }
+            ChatCompletionRequestMessage::Developer(x) => {
+                system.push(match &x.content {
+                    ChatCompletionRequestDeveloperMessageContent::Text(text) => text.clone(),
+                    ChatCompletionRequestDeveloperMessageContent::Array(parts) => {
+                        parts.iter().map(|x| x.text.as_str()).collect()
+                    }
+                });
+                continue;
+            }
+            ChatCompletionRequestMessage::User(x) => {
+                let blocks = match &x.content {
+                    ChatCompletionRequestUserMessageContent::Text(text) => vec![text_block(text)],
+                    ChatCompletionRequestUserMessageContent::Array(parts) => parts
+                        .iter()
+                        .filter_map(|part| match part {
+                            ChatCompletionRequestUserMessageContentPart::Text(x) => {
+                                Some(text_block(&x.text))
+                            }
+                            ChatCompletionRequestUserMessageContentPart::ImageUrl(x) => {
+                                Some(image_block(&x.image_url.url))
+                            }
+                            ChatCompletionRequestUserMessageContentPart::InputAudio(_) => None,
+                        })
+                        .collect(),
+                };
+                ("user", blocks)
+            }
+            ChatCompletionRequestMessage::Assistant(x) => {
+                let mut blocks = match &x.content {
+                    Some(ChatCompletionRequestAssistantMessageContent::Text(text)) => {
+                        vec![text_block(text)]
+                    }
+                    Some(ChatCompletionRequestAssistantMessageContent::Array(parts)) => parts
+                        .iter()
+                        .map(|part| match part {
+                            ChatCompletionRequestAssistantMessageContentPart::Text(x) => {
+                                text_block(&x.text)
+                            }
+                            ChatCompletionRequestAssistantMessageContentPart::Refusal(x) => {
+                                text_block(&x.refusal)
+                            }
+                        })
+                        .collect(),
+                    None => vec![],
+                };
+                for call in x.tool_calls.iter().flatten() {
+                    let input = serde_json::from_str::<Value>(&call.function.arguments)
+                        .ok()
+                        .filter(Value::is_object)
+                        .unwrap_or(json!({}));
+                    blocks.push(json!({
+                        "type": "tool_use",
+                        "id": call.id,
+                        "name": call.function.name,
+                        "input": input,
+                    }));
+                }
+                ("assistant", blocks)
+            }
+            ChatCompletionRequestMessage::Tool(x) => {
+                let content = match &x.content {
+                    ChatCompletionRequestToolMessageContent::Text(text) => text.clone(),
+                    ChatCompletionRequestToolMessageContent::Array(parts) => parts
+                        .iter()
+                        .map(|ChatCompletionRequestToolMessageContentPart::Text(x)| x.text.as_str())
+                        .collect(),
+                };
+                let block = json!({
+                    "type": "tool_result",
+                    "tool_use_id": x.tool_call_id,
+                    "content": content,
+                });
+                ("user", vec![block])
+            }
+            ChatCompletionRequestMessage::Function(_) => continue,
+        };
+        // Empty text blocks are rejected
+        let blocks: Vec<Value> = blocks
+            .into_iter()
+            .filter(|x| x["type"] != "text" || x["text"] != "")
+            .collect();
+        match converted.last_mut() {
+            _ if blocks.is_empty() => {}
+            Some((last_role, last_blocks)) if *last_role == role => last_blocks.extend(blocks),
+            _ => converted.push((role, blocks)),
+        }
+    }
+    let converted = converted
+        .into_iter()
+        .map(|(role, content)| json!({"role": role, "content": content}))
+        .collect();
+    (system.join("\n\n"), converted)
+}
+
+fn text_block(text: &str) -> Value {
+    json!({"type": "text", "text": text})
+}
+
+// The images from tools are data URLs
+fn image_block(url: &str) -> Value {
+    let data = url
+        .strip_prefix("data:")
+        .and_then(|x| x.split_once(";base64,"));
+    match data {
+        Some((media_type, data)) => json!({
+            "type": "image",
+            "source": {"type": "base64", "media_type": media_type, "data": data},
+        }),
+        None => json!({
+            "type": "image",
+            "source": {"type": "url", "url": url},
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::RetryConfig;
+    use async_openai::types::{
+        ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestSystemMessageArgs,
+        ChatCompletionRequestToolMessageArgs, ChatCompletionRequestUserMessageArgs,
+    };
+    use tokio::{
+        io::{AsyncReadExt as _, AsyncWriteExt as _},
+        net::TcpListener,
+    };
+
+    fn history() -> Vec<ChatCompletionRequestMessage> {
+        let call = |id: &str| ChatCompletionMessageToolCall {
+            id: String::from(id),
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionCall {
+                name: String::from("mealie__add_to_list"),
+                arguments: String::from("{\"name\": \"milk\"}"),
+            },
+        };
+        let tool = |id: &str| -> ChatCompletionRequestMessage {
+            ChatCompletionRequestToolMessageArgs::default()
+                .tool_call_id(id)
+                .content("added")
+                .build()
+                .unwrap()
+                .into()
+        };
+        vec![
+            ChatCompletionRequestSystemMessageArgs::default()
+                .content("Be brief")
+                .build()
+                .unwrap()
+                .into(),
+            ChatCompletionRequestUserMessageArgs::default()
+                .content("Add milk twice")
+                .build()
+                .unwrap()
+                .into(),
+            ChatCompletionRequestAssistantMessageArgs::default()
+                .tool_calls(vec![call("a"), call("b")])
+                .build()
+                .unwrap()
+                .into(),
+            tool("a"),
+            tool("b"),
+        ]
+    }
+
+    #[test]
+    fn test_convert_messages() {
+        let (system, messages) = convert_messages(&history());
+        assert_eq!("Be brief", system);
+        assert_eq!(
+            json!([
+                {"role": "user", "content": [{"type": "text", "text": "Add milk twice"}]},
+                {"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "a", "name": "mealie__add_to_list", "input": {"name": "milk"}},
+                    {"type": "tool_use", "id": "b", "name": "mealie__add_to_list", "input": {"name": "milk"}},
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "a", "content": "added"},
+                    {"type": "tool_result", "tool_use_id": "b", "content": "added"},
+                ]},
+            ]),
+            Value::Array(messages)
+        );
+    }
+
+    // A server that answers a single request with the given server-sent events and hands the
+    // request back
+    async fn mock_server(events: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v1", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buffer = [0; 4096];
+            // The headers and then as much body as the headers say
+            loop {
+                let n = socket.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..n]);
+                let text = String::from_utf8_lossy(&request).to_lowercase();
+                if let Some((headers, body)) = text.split_once("\r\n\r\n") {
+                    let length = headers
+                        .lines()
+                        .find_map(|x| x.strip_prefix("content-length: "))
+                        .and_then(|x| x.trim().parse::<usize>().ok())
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        break;
+                    }
+                }
+            }
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\nconnection: close\r\n\r\n{events}"
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            socket.shutdown().await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (url, handle)
+    }
+
+    #[tokio::test]
+    async fn test_stream_against_mock_server() {
+        let events = concat!(
+            "event: message_start\ndata: {\"type\": \"message_start\", \"message\": {\"usage\": {\"input_tokens\": 25, \"output_tokens\": 1}}}\n\n",
+            "event: content_block_start\ndata: {\"type\": \"content_block_start\", \"index\": 0, \"content_block\": {\"type\": \"text\", \"text\": \"\"}}\n\n",
+            "event: content_block_delta\ndata: {\"type\": \"content_block_delta\", \"index\": 0, \"delta\": {\"type\": \"text_delta\", \"text\": \"Adding \"}}\n\n",
+            "event: ping\ndata: {\"type\": \"ping\"}\n\n",
+            "event: content_block_delta\ndata: {\"type\": \"content_block_delta\", \"index\": 0, \"delta\": {\"type\": \"text_delta\", \"text\": \"milk\"}}\n\n",
+            "event: content_block_stop\ndata: {\"type\": \"content_block_stop\", \"index\": 0}\n\n",
+            "event: content_block_start\ndata: {\"type\": \"content_block_start\", \"index\": 1, \"content_block\": {\"type\": \"tool_use\", \"id\": \"toolu_1\", \"name\": \"mealie__add_to_list\", \"input\": {}}}\n\n",
+            "event: content_block_delta\ndata: {\"type\": \"content_block_delta\", \"index\": 1, \"delta\": {\"type\": \"input_json_delta\", \"partial_json\": \"{\\\"name\\\": \"}}\n\n",
+            "event: content_block_delta\ndata: {\"type\": \"content_block_delta\", \"index\": 1, \"delta\": {\"type\": \"input_json_delta\", \"partial_json\": \"\\\"milk\\\"}\"}}\n\n",
+            "event: content_block_stop\ndata: {\"type\": \"content_block_stop\", \"index\": 1}\n\n",
+            "event: message_delta\ndata: {\"type\": \"message_delta\", \"delta\": {\"stop_reason\": \"tool_use\"}, \"usage\": {\"output_tokens\": 40}}\n\n",
+            "event: message_stop\ndata: {\"type\": \"message_stop\"}\n\n",
+        );
+        let (base_url, server) = mock_server(events).await;
+        let client = AnthropicClient {
+            http: reqwest::Client::new(),
+            base_url,
+            api_key: String::from("secret"),
+            model: String::from("claude-test"),
+            tools: vec![],
+            stream: true,
+            retry: RetryConfig::default(),
+            timeout: Duration::from_secs(5),
+            sampling: Sampling::default(),
+        };
+        let mut streamed = String::new();
+        let reply = client
+            .chat(&history()[..2], &Sampling::default(), &mut |x| {
+                streamed.push_str(x)
+            })
+            .await
+            .unwrap();
+        assert_eq!("Adding milk", streamed);
+        assert_eq!("Adding milk", reply.content);
+        let calls: Vec<(&str, &str, &str)> = reply
+            .tool_calls
+            .iter()
+            .map(|x| {
+                (
+                    x.id.as_str(),
+                    x.function.name.as_str(),
+                    x.function.arguments.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![("toolu_1", "mealie__add_to_list", "{\"name\": \"milk\"}")],
+            calls
+        );
+        let usage = reply.usage.unwrap();
+        assert_eq!((25, 40), (usage.prompt_tokens, usage.completion_tokens));
+
+        let request = server.await.unwrap();
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("POST /v1/messages "));
+        assert!(head.contains("x-api-key: secret"));
+        assert!(head.contains("anthropic-version: 2023-06-01"));
+        let body: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(json!("Be brief"), body["system"]);
+        assert_eq!(json!(true), body["stream"]);
+        assert_eq!(json!("claude-test"), body["model"]);
+    }
+}
@@ -0,0 +1,80 @@
+use std::{collections::HashSet, io::IsTerminal as _};
+
+use async_openai::types::FunctionCall;
+use rmcp::serde_json::{self, Value};
+use rustyline::{Behavior, Config, DefaultEditor};
+
+use crate::conf::{ToolPolicy, ToolsConfig};
+
+pub enum Verdict {
+    Allow,
+    // The reason is given to the model as the result of the call
+    Deny(String),
+}
+
+// Decides whether the tool calls may run, asking the user for the tools with the `ask` policy
+pub struct Approvals {
+    // Without a terminal there's nobody to ask, and `ask` means `deny`
+    interactive: bool,
+    // Tools that the user allowed for the rest of the session
+    always: HashSet<String>,
+}
+
+impl Approvals {
+    pub fn new() -> Approvals {
+        Approvals {
+            interactive: std::io::stdin().is_terminal(),
+            always: HashSet::new(),
+        }
+    }
+
+    pub fn check(&mut self, conf: &ToolsConfig, call: &FunctionCall) -> Verdict {
+        match conf.policy_for(&call.name) {
+            ToolPolicy::Allow => Verdict::Allow,
+            ToolPolicy::Deny => Verdict::Deny(format!(
+                "Denied: the configuration doesn't allow calling '{}'",
+                call.name
+            )),
+            ToolPolicy::Ask if self.always.contains(&call.name) => Verdict::Allow,
+            ToolPolicy::Ask if !self.interactive => Verdict::Deny(format!(
+                "Denied: calling '{}' needs an approval from the user, but nobody is there to ask",
+                call.name
+            )),
+            ToolPolicy::Ask => self.ask(call),
+        }
+    }
+
+    fn ask(&mut self, call: &FunctionCall) -> Verdict {
+        let arguments = serde_json::from_str::<Value>(&call.arguments)
+            .and_then(|x| serde_json::to_string_pretty(&x))
+            .unwrap_or(call.arguments.clone());
+        // Not on stdout, which has only the answer when rullm is run with `--prompt`
+        eprintln!(
+            "\nThe model wants to call {} with\n{}",
+            call.name, arguments
+        );
+        let config = Config::builder().behavior(Behavior::PreferTerm).build();
+        let Ok(mut rl) = DefaultEditor::with_config(config) else {
+            return Verdict::Deny(String::from("Denied: couldn't ask the user"));
+        };
+        // Ctrl-C and Ctrl-D count as a no
+        while let Ok(answer) = rl.readline("Allow it? [y]es, [n]o, [a]lways in this session: ") {
+            match answer.trim().to_lowercase().as_str() {
+                "y" | "yes" => return Verdict::Allow,
+                "a" | "always" => {
+                    self.always.insert(call.name.clone());
+                    return Verdict::Allow;
+                }
+                "n" | "no" => break,
+                _ => continue,
+            }
+        }
+        Verdict::Deny(String::from("Denied: the user didn't allow this tool call"))
+    }
+}
+
+impl Default for Approvals {
+    fn default() -> Self {
+        Approvals::new()
+    }
+}
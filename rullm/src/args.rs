@@ -1,5 +1,9 @@
-use std::path::PathBuf;
+use std::{
+    io::{IsTerminal as _, Read as _},
+    path::PathBuf,
+};
 
+use anyhow::bail;
 use clap::Parser;
 
 #[derive(Parser, Debug)]
@@ -8,5 +12,59 @@ pub struct Args {
     #[arg(short, long)]
     // This should be a vector eventually
     pub conf_file: Option<PathBuf>,
+
+    /// Resume the session with the given id
+    #[arg(short, long, conflicts_with = "continue_session")]
+    pub resume: Option<String>,
+
+    /// Resume the most recent session
+    #[arg(long = "continue")]
+    pub continue_session: bool,
+
+    /// List the saved sessions and exit
+    #[arg(long)]
+    pub list_sessions: bool,
+
+    /// Answer a single prompt and exit. Without it the prompt is read from stdin when stdin
+    /// isn't a terminal and no session is resumed
+    #[arg(short, long, conflicts_with_all = ["resume", "continue_session"])]
+    pub prompt: Option<String>,
+
+    /// Use the system prompt of the given persona from the configuration
+    #[arg(long, conflicts_with_all = ["resume", "continue_session"])]
+    pub persona: Option<String>,
+
+    /// Use the given profile from `[llm.profiles]` instead of the default one
+    #[arg(long)]
+    pub profile: Option<String>,
+
+    /// Print the answer of a single prompt as JSON, together with the tool calls
+    #[arg(long, conflicts_with_all = ["resume", "continue_session", "list_sessions"])]
+    pub json: bool,
 }
 
+impl Args {
+    // The prompt for the non-interactive mode, if rullm is run that way
+    pub fn one_shot_prompt(&self) -> anyhow::Result<Option<String>> {
+        if let Some(prompt) = &self.prompt {
+            return Ok(Some(prompt.clone()));
+        }
+        // A session is resumed in the REPL, whatever stdin is
+        if self.resume.is_some() || self.continue_session || self.list_sessions {
+            return Ok(None);
+        }
+        let mut stdin = std::io::stdin();
+        if stdin.is_terminal() {
+            if self.json {
+                bail!("--json is for a single prompt, give one with --prompt or on stdin")
+            }
+            return Ok(None);
+        }
+        let mut prompt = String::new();
+        stdin.read_to_string(&mut prompt)?;
+        if prompt.trim().is_empty() {
+            bail!("The prompt read from stdin is empty")
+        }
+        Ok(Some(prompt))
+    }
+}
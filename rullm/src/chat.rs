@@ -1,39 +1,115 @@
-use crate::env::Env;
-use anyhow::bail;
+use crate::{
+    approval::Verdict,
+    commands::{Action, Command, CommandCompleter},
+    conf::{ImageFallback, LLMConfig},
+    context,
+    env::Env,
+    message::is_turn_start,
+    prompt,
+    provider::Reply,
+    session::Session,
+};
+use anyhow::anyhow;
 use async_openai::{
     error::OpenAIError,
     types::{
         ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessage,
         ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
-        ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestToolMessage,
-        ChatCompletionRequestToolMessageArgs, ChatCompletionRequestUserMessage,
-        ChatCompletionRequestUserMessageArgs,
+        ChatCompletionRequestMessageContentPartImage, ChatCompletionRequestMessageContentPartText,
+        ChatCompletionRequestSystemMessage, ChatCompletionRequestSystemMessageArgs,
+        ChatCompletionRequestToolMessage, ChatCompletionRequestToolMessageArgs,
+        ChatCompletionRequestToolMessageContent, ChatCompletionRequestUserMessage,
+        ChatCompletionRequestUserMessageArgs, ChatCompletionRequestUserMessageContentPart,
+        ImageDetail, ImageUrl,
     },
 };
+use base64::{Engine as _, prelude::BASE64_STANDARD};
 use chrono::Utc;
-use rmcp::model::{RawContent, RawTextContent};
-use rustyline::{DefaultEditor, error::ReadlineError};
-
-pub async fn run(env: Env) -> anyhow::Result<()> {
-    let mut rl = DefaultEditor::new()?;
-    let mut messages: Vec<ChatCompletionRequestMessage> = vec![];
-    let system_prompt = format!(
-        "You are a helpful assistant. You know that today is {}",
-        Utc::now().date_naive().format("%Y-%m-%d").to_string()
-    );
-    messages.push(
-        ChatCompletionRequestSystemMessageArgs::default()
-            .content(system_prompt)
-            .build()?
-            .into(),
-    );
+use futures::{StreamExt as _, stream};
+use rmcp::{
+    model::{
+        CallToolResult, RawContent, RawEmbeddedResource, RawImageContent, RawTextContent,
+        ResourceContents,
+    },
+    serde_json::{self, Value, json},
+};
+use rustyline::{Editor, error::ReadlineError, history::FileHistory};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::Write as _,
+    path::PathBuf,
+};
+use tracing::warn;
+
+pub async fn run(mut env: Env, session: Option<Session>) -> anyhow::Result<()> {
+    let mut rl: Editor<CommandCompleter, FileHistory> = Editor::new()?;
+    rl.set_helper(Some(CommandCompleter));
+    let mut session = match session {
+        Some(session) => {
+            eprintln!("Resuming session {}", session.id);
+            session
+        }
+        None => {
+            let mut session = env.new_session();
+            session.messages.push(system_message(&env)?.into());
+            session
+        }
+    };
     loop {
         let readline = rl.readline(">> ");
         match readline {
             Ok(line) => {
-                let assistant_response = chat(&env, &mut messages, &line).await?;
-                println!("{}", &assistant_response);
+                let action = Command::parse(&line).and_then(|command| match command {
+                    Some(command) => command.execute(&mut env, &mut session),
+                    None => Ok(Action::Send(line)),
+                });
+                let line = match action {
+                    Ok(Action::Send(line)) => line,
+                    Ok(Action::Continue) => continue,
+                    Ok(Action::Quit) => break,
+                    Err(err) => {
+                        println!("{err}");
+                        continue;
+                    }
+                };
+                let mut print_token = |token: &str| {
+                    print!("{token}");
+                    let _ = std::io::stdout().flush();
+                };
+                let result = tokio::select! {
+                    // The question is added to the history right away, before Ctrl-C is
+                    // looked at, so the rollback below always finds it
+                    biased;
+                    result = chat(&mut env, &mut session.messages, &line, &mut print_token) => {
+                        Some(result)
+                    }
+                    _ = tokio::signal::ctrl_c() => None,
+                };
+                println!();
+                let Some(result) = result else {
+                    // Dropping the turn also cancels the tool calls that were running
+                    if let Some(turn) = session.messages.iter().rposition(is_turn_start) {
+                        session.messages.truncate(turn);
+                    }
+                    session.failed = Some(line);
+                    println!("Cancelled");
+                    continue;
+                };
+                if let Err(err) = result {
+                    session.failed = Some(line);
+                    println!("Error: {err:#}");
+                    continue;
+                }
+                // /retry goes for this turn from now on
+                session.failed = None;
+                // Saving after every answer so that nothing is lost if rullm is killed
+                if let Err(err) = session.save(&env.usage) {
+                    eprintln!("Failed to save the session: {err:?}");
+                }
             }
+            // Ctrl-C at the prompt only clears the line, Ctrl-D quits
+            Err(ReadlineError::Interrupted) => continue,
             Err(ReadlineError::Eof) => break,
             Err(err) => {
                 println!("Error: {:?}", err);
@@ -41,6 +117,49 @@ pub async fn run(env: Env) -> anyhow::Result<()> {
             }
         }
     }
+    if !env.usage.is_empty() {
+        env.usage.print(&env.conf.llm.prices);
+    }
+    Ok(())
+}
+
+// Answer a single prompt without the REPL. Only the final answer is printed, or with 'json' an
+// object with the answer and the tool calls that were made along the way
+pub async fn one_shot(mut env: Env, prompt: &str, json: bool) -> anyhow::Result<()> {
+    let mut messages: Vec<ChatCompletionRequestMessage> = vec![system_message(&env)?.into()];
+    let start = messages.len();
+    let answer = chat(&mut env, &mut messages, prompt, &mut |_| {}).await?;
+    if !json {
+        println!("{answer}");
+        return Ok(());
+    }
+    let results: HashMap<&str, &ChatCompletionRequestToolMessageContent> = messages[start..]
+        .iter()
+        .filter_map(|message| match message {
+            ChatCompletionRequestMessage::Tool(tool) => {
+                Some((tool.tool_call_id.as_str(), &tool.content))
+            }
+            _ => None,
+        })
+        .collect();
+    let tool_calls: Vec<Value> = messages[start..]
+        .iter()
+        .filter_map(|message| match message {
+            ChatCompletionRequestMessage::Assistant(assistant) => assistant.tool_calls.as_ref(),
+            _ => None,
+        })
+        .flatten()
+        .map(|call| {
+            let arguments = serde_json::from_str(&call.function.arguments)
+                .unwrap_or(Value::String(call.function.arguments.clone()));
+            json!({
+                "name": call.function.name,
+                "arguments": arguments,
+                "result": results.get(call.id.as_str()),
+            })
+        })
+        .collect();
+    println!("{}", json!({"answer": answer, "tool_calls": tool_calls}));
     Ok(())
 }
 
@@ -48,66 +167,208 @@ pub async fn run(env: Env) -> anyhow::Result<()> {
 // Will keep track of message history via the 'messages' field
 //
 // In case of function calling, there might be more than one request involved
+//
+// The answer is passed to 'on_token' piece by piece as it is generated
 async fn chat(
-    env: &Env,
+    env: &mut Env,
     messages: &mut Vec<ChatCompletionRequestMessage>,
     line: &str,
+    on_token: &mut dyn FnMut(&str),
 ) -> anyhow::Result<String> {
-    let message = user_message(&line)?;
+    let message = user_message(line)?;
     messages.push(message.into());
-    // There's a risk that LLM will keep on calling functions
-    let mut limit_counter: i8 = 5;
-    loop {
-        if limit_counter <= 0 {
-            bail!("Too many LLM requests")
-        }
+    let mut turn = messages.len() - 1;
+    let result = answer(env, messages, &mut turn, on_token).await;
+    if result.is_err() {
+        // Whatever was added for this question is dropped, an assistant message with tool
+        // calls but without the results would be rejected by the API
+        messages.truncate(turn);
+    }
+    result
+}
 
-        let response = env.openai_client.chat(&messages).await?;
-        let mut text_responses: Vec<String> = vec![];
-        let mut tool_calls: Vec<ChatCompletionMessageToolCall> = vec![];
-        for message in response.choices.into_iter().map(|x| x.message) {
-            if let Some(content) = message.content {
-                text_responses.push(content);
-            }
-            if let Some(tools) = message.tool_calls {
-                tool_calls.extend(tools);
-            }
-        }
-        let assistant_response = text_responses.join("\n");
+// The requests of a single turn, which starts at `turn`. Older turns may be dropped to fit the
+// context, so `turn` is kept up to date
+async fn answer(
+    env: &mut Env,
+    messages: &mut Vec<ChatCompletionRequestMessage>,
+    turn: &mut usize,
+    on_token: &mut dyn FnMut(&str),
+) -> anyhow::Result<String> {
+    // There's a risk that LLM will keep on calling functions
+    let limit = env.conf.llm.max_tool_iterations.unwrap_or(5);
+    let mut called: BTreeMap<String, usize> = BTreeMap::new();
+    for _ in 0..limit {
+        *turn -= context::fit(env, messages, *turn).await?;
+        let Reply {
+            content: assistant_response,
+            tool_calls,
+            usage,
+        } = env.llm.chat(messages, &env.overrides, on_token).await?;
+        env.usage.record(env.llm.model(), usage.as_ref());
         if tool_calls.is_empty() {
             messages.push(assistant_message(&assistant_response, None)?.into());
             // Early return, no function calls
             return Ok(assistant_response);
         } else {
+            for call in &tool_calls {
+                *called.entry(call.function.name.clone()).or_default() += 1;
+            }
             // Do the tool calling machinery
             let new_messages =
-                process_function_calls(&env, &assistant_response, &tool_calls).await?;
+                process_function_calls(env, &assistant_response, &tool_calls).await?;
             messages.extend(new_messages);
         }
-        limit_counter -= 1;
     }
+    // Ending the turn with an assistant message keeps the history valid for the next question
+    let tools = called
+        .iter()
+        .map(|(name, count)| format!("{name} ({count}x)"))
+        .collect::<Vec<String>>()
+        .join(", ");
+    let note =
+        format!("Stopped after {limit} rounds of tool calls. The tools called were: {tools}");
+    messages.push(assistant_message(&note, None)?.into());
+    on_token(&note);
+    Ok(note)
 }
 
+// Every tool call gets a tool message, also when the call fails or is denied. That way the
+// model sees what went wrong and can try again with different arguments
+//
+// Tool messages can only hold text, so images are passed on in a user message after them
 async fn process_function_calls(
-    env: &Env,
+    env: &mut Env,
     assistant_response: &str,
     tool_calls: &[ChatCompletionMessageToolCall],
 ) -> anyhow::Result<Vec<ChatCompletionRequestMessage>> {
     let mut messages = vec![];
-    messages.push(assistant_message(&assistant_response, Some(Vec::from(tool_calls)))?.into());
-    for call in Vec::from(tool_calls) {
-        let id = call.id;
-        let response = env.mcp.call_tool(&call.function).await?;
-        let mut text_response = String::new();
-        for raw in response.content.into_iter().map(|x| x.raw) {
-            match raw {
-                RawContent::Text(RawTextContent { text }) => text_response.push_str(&text),
-                x => bail!("Unknown response: {:?}", x),
+    messages.push(assistant_message(assistant_response, Some(Vec::from(tool_calls)))?.into());
+    // The user is asked about one call at a time before any of them runs
+    let verdicts: Vec<Verdict> = tool_calls
+        .iter()
+        .map(|call| env.approvals.check(&env.conf.tools, &call.function))
+        .collect();
+    // The calls run concurrently, but `buffered` keeps the results in the order of the calls
+    let env = &*env;
+    let parallelism = env.conf.tools.max_parallel.max(1);
+    let outputs: Vec<ToolOutput> = stream::iter(tool_calls.iter().zip(verdicts))
+        .map(|(call, verdict)| call_tool(env, call, verdict))
+        .buffered(parallelism)
+        .collect()
+        .await;
+    let mut images: Vec<(String, RawImageContent)> = vec![];
+    for (call, output) in tool_calls.iter().zip(outputs) {
+        messages.push(tool_message(&call.id, &output.text)?.into());
+        images.extend(output.images.into_iter().map(|x| (call.id.clone(), x)));
+    }
+    if !images.is_empty() {
+        messages.push(image_message(&images)?.into());
+    }
+    Ok(messages)
+}
+
+async fn call_tool(
+    env: &Env,
+    call: &ChatCompletionMessageToolCall,
+    verdict: Verdict,
+) -> ToolOutput {
+    if let Verdict::Deny(reason) = verdict {
+        return ToolOutput {
+            text: reason,
+            images: vec![],
+        };
+    }
+    match env.mcp.call_tool(&call.function).await {
+        Ok(response) => tool_output(&env.conf.llm, response),
+        Err(err) => {
+            warn!(tool = call.function.name, "Tool call failed: {err:#}");
+            ToolOutput {
+                text: format!("Error: the tool call failed: {err:#}"),
+                images: vec![],
             }
         }
-        messages.push(tool_message(&id, &text_response)?.into());
     }
-    Ok(messages)
+}
+
+// The text for the tool message and the images that have to be sent separately
+struct ToolOutput {
+    text: String,
+    images: Vec<RawImageContent>,
+}
+
+fn tool_output(conf: &LLMConfig, response: CallToolResult) -> ToolOutput {
+    let vision = conf.vision.unwrap_or(false);
+    let fallback = conf.image_fallback.unwrap_or_default();
+    let mut text_response = String::new();
+    let mut images = vec![];
+    for raw in response.content.into_iter().map(|x| x.raw) {
+        match raw {
+            RawContent::Text(RawTextContent { text }) => text_response.push_str(&text),
+            RawContent::Image(image) if vision => {
+                text_response.push_str(&format!(
+                    "[{} image, attached to the next message]",
+                    image.mime_type
+                ));
+                images.push(image);
+            }
+            RawContent::Image(image) => match fallback {
+                ImageFallback::Placeholder => text_response.push_str(&format!(
+                    "[{} image, not shown because the model doesn't accept images]",
+                    image.mime_type
+                )),
+                ImageFallback::Save => match save_image(&image) {
+                    Ok(path) => text_response.push_str(&format!(
+                        "[{} image, saved to {}]",
+                        image.mime_type,
+                        path.display()
+                    )),
+                    Err(err) => text_response.push_str(&format!(
+                        "[{} image, failed to save it: {err}]",
+                        image.mime_type
+                    )),
+                },
+            },
+            RawContent::Resource(RawEmbeddedResource { resource }) => match resource {
+                ResourceContents::TextResourceContents { uri, text, .. } => {
+                    text_response.push_str(&format!("Resource {uri}:\n{text}"))
+                }
+                ResourceContents::BlobResourceContents { uri, mime_type, .. } => text_response
+                    .push_str(&format!(
+                        "[Resource {uri} ({}), binary content not shown]",
+                        mime_type.as_deref().unwrap_or("unknown type")
+                    )),
+            },
+        }
+    }
+    let text = if response.is_error == Some(true) {
+        format!("Error: the tool reported an error: {text_response}")
+    } else {
+        text_response
+    };
+    ToolOutput { text, images }
+}
+
+// Images are stored under `<data dir>/rullm/images`
+fn save_image(image: &RawImageContent) -> anyhow::Result<PathBuf> {
+    let data = BASE64_STANDARD.decode(&image.data)?;
+    let mut path = dirs_next::data_dir().ok_or(anyhow!("Data directory missing"))?;
+    path.push("rullm");
+    path.push("images");
+    fs::create_dir_all(&path)?;
+    let extension = image.mime_type.rsplit('/').next().unwrap_or("bin");
+    path.push(format!(
+        "{}.{extension}",
+        Utc::now().format("%Y%m%d-%H%M%S-%f")
+    ));
+    fs::write(&path, data)?;
+    Ok(path)
+}
+
+fn system_message(env: &Env) -> anyhow::Result<ChatCompletionRequestSystemMessage> {
+    Ok(ChatCompletionRequestSystemMessageArgs::default()
+        .content(prompt::system_prompt(env)?)
+        .build()?)
 }
 
 fn user_message(msg: &str) -> Result<ChatCompletionRequestUserMessage, OpenAIError> {
@@ -128,9 +389,106 @@ fn assistant_message(
     builder.build()
 }
 
+fn image_message(
+    images: &[(String, RawImageContent)],
+) -> Result<ChatCompletionRequestUserMessage, OpenAIError> {
+    let mut parts = vec![ChatCompletionRequestUserMessageContentPart::Text(
+        ChatCompletionRequestMessageContentPartText {
+            text: String::from("The images returned by the tool calls"),
+        },
+    )];
+    for (id, image) in images {
+        parts.push(ChatCompletionRequestUserMessageContentPart::Text(
+            ChatCompletionRequestMessageContentPartText {
+                text: format!("Tool call {id}:"),
+            },
+        ));
+        parts.push(ChatCompletionRequestUserMessageContentPart::ImageUrl(
+            ChatCompletionRequestMessageContentPartImage {
+                image_url: ImageUrl {
+                    url: format!("data:{};base64,{}", image.mime_type, image.data),
+                    detail: Some(ImageDetail::Auto),
+                },
+            },
+        ));
+    }
+    ChatCompletionRequestUserMessageArgs::default()
+        .content(parts)
+        .build()
+}
+
 fn tool_message(id: &str, content: &str) -> Result<ChatCompletionRequestToolMessage, OpenAIError> {
     ChatCompletionRequestToolMessageArgs::default()
         .content(content)
         .tool_call_id(id)
         .build()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{Config, File, FileFormat};
+    use rmcp::model::Content;
+
+    fn llm_conf(toml: &str) -> LLMConfig {
+        Config::builder()
+            .add_source(File::from_str(toml, FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    fn content() -> Vec<Content> {
+        vec![
+            Content::text("Found it. "),
+            Content::image("aGk=", "image/png"),
+            Content::embedded_text("file:///notes.txt", "milk"),
+            Content::resource(ResourceContents::BlobResourceContents {
+                uri: String::from("file:///photo.jpg"),
+                mime_type: None,
+                blob: String::from("aGk="),
+            }),
+        ]
+    }
+
+    #[test]
+    fn test_tool_output() {
+        let output = tool_output(
+            &llm_conf("vision = true"),
+            CallToolResult::success(content()),
+        );
+        assert_eq!(
+            "Found it. [image/png image, attached to the next message]\
+             Resource file:///notes.txt:\nmilk\
+             [Resource file:///photo.jpg (unknown type), binary content not shown]",
+            output.text
+        );
+        assert_eq!(1, output.images.len());
+
+        let output = tool_output(&llm_conf(""), CallToolResult::error(content()));
+        assert!(
+            output.text.starts_with(
+                "Error: the tool reported an error: Found it. [image/png image, not shown \
+                 because the model doesn't accept images]"
+            ),
+            "{}",
+            output.text
+        );
+        assert!(output.images.is_empty());
+
+        // An image that can't be decoded isn't saved, the model is told why
+        let image = Content::image("not base64", "image/png");
+        let output = tool_output(
+            &llm_conf("image_fallback = \"save\""),
+            CallToolResult::success(vec![image]),
+        );
+        assert!(
+            output
+                .text
+                .starts_with("[image/png image, failed to save it:"),
+            "{}",
+            output.text
+        );
+    }
+}
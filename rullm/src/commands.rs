@@ -0,0 +1,261 @@
+use anyhow::{anyhow, bail};
+use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs};
+use rustyline::{
+    Context, Helper, completion::Completer, highlight::Highlighter, hint::Hinter,
+    validate::Validator,
+};
+
+use crate::{
+    env::Env,
+    message::{message_role, message_text},
+    session::Session,
+    usage::Usage,
+};
+
+// The REPL lines starting with a '/'
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Tools,
+    Model(Option<String>),
+    Profile(Option<String>),
+    Set(Option<String>),
+    Unset(String),
+    Clear,
+    Save,
+    System(Option<String>),
+    History,
+    Usage,
+    Retry,
+    Help,
+    Quit,
+    // A message that starts with a '/', typed with two of them
+    Message(String),
+}
+
+// What the REPL should do after a command
+pub enum Action {
+    Continue,
+    // Send a message to the model as if the user had typed it
+    Send(String),
+    Quit,
+}
+
+// Name, arguments and a description, for /help and the tab completion
+const COMMANDS: &[(&str, &str, &str)] = &[
+    ("/tools", "", "List the tools the model can use"),
+    ("/model", "[name]", "Show or switch the model"),
+    ("/profile", "[name]", "List the profiles or switch to one"),
+    ("/set", "[name value]", "Show or change a sampling setting"),
+    ("/unset", "<name>", "Go back to the configured setting"),
+    ("/clear", "", "Start over, keeping the system prompt"),
+    ("/save", "", "Save the session now"),
+    ("/system", "[text]", "Show or replace the system prompt"),
+    ("/history", "", "Show the messages of this session"),
+    ("/usage", "", "Show the tokens used in this session"),
+    ("/retry", "", "Send the last message again"),
+    ("/help", "", "Show this help"),
+    ("/quit", "", "Exit rullm"),
+];
+
+impl Command {
+    // Ok(None) means that the line is a regular message for the model
+    pub fn parse(line: &str) -> anyhow::Result<Option<Command>> {
+        if let Some(message) = line.trim_start().strip_prefix("//") {
+            return Ok(Some(Command::Message(format!("/{message}"))));
+        }
+        let Some(line) = line.trim().strip_prefix('/') else {
+            return Ok(None);
+        };
+        let (name, argument) = match line.split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, Some(argument.trim().to_string())),
+            None => (line, None),
+        };
+        let argument = argument.filter(|x| !x.is_empty());
+        let command = match name {
+            "tools" => Command::Tools,
+            "model" => Command::Model(argument),
+            "profile" => Command::Profile(argument),
+            "set" => Command::Set(argument),
+            "unset" => Command::Unset(argument.ok_or(anyhow!("Usage: /unset <name>"))?),
+            "clear" => Command::Clear,
+            "save" => Command::Save,
+            "system" => Command::System(argument),
+            "history" => Command::History,
+            "usage" => Command::Usage,
+            "retry" => Command::Retry,
+            "help" => Command::Help,
+            "quit" | "exit" => Command::Quit,
+            _ => bail!("Unknown command '/{name}', see /help. Start a message with // to send it"),
+        };
+        Ok(Some(command))
+    }
+
+    pub fn execute(self, env: &mut Env, session: &mut Session) -> anyhow::Result<Action> {
+        match self {
+            Command::Tools => {
+                for tool in env.llm.tools() {
+                    let description = tool.function.description.as_deref().unwrap_or("");
+                    println!("{}: {}", tool.function.name, description);
+                }
+            }
+            Command::Model(None) => println!("{}", env.llm.model()),
+            Command::Model(Some(model)) => {
+                env.llm.set_model(&model);
+                session.model = model;
+            }
+            Command::Profile(None) => {
+                let mut names: Vec<&String> = env.conf.llm.profiles.keys().collect();
+                names.sort();
+                if names.is_empty() {
+                    println!("There are no profiles");
+                }
+                for name in names {
+                    let current = env.profile.as_ref() == Some(name);
+                    println!("{} {name}", if current { "*" } else { " " });
+                }
+            }
+            Command::Profile(Some(name)) => {
+                env.switch_profile(&name)?;
+                session.model = String::from(env.llm.model());
+                session.profile = env.profile.clone();
+                session.provider = Some(env.provider);
+                println!("Using {}", env.llm.model());
+            }
+            Command::Set(None) => {
+                let sampling = env.overrides.or(env.llm.sampling());
+                let settings = sampling.describe();
+                if settings.is_empty() {
+                    println!("Everything is left to the provider");
+                }
+                for (name, value) in settings {
+                    println!("{name} = {value}");
+                }
+            }
+            Command::Set(Some(argument)) => {
+                let (name, value) = argument
+                    .split_once(char::is_whitespace)
+                    .ok_or(anyhow!("Usage: /set <name> <value>"))?;
+                env.overrides.set(name, Some(value.trim()))?;
+            }
+            Command::Unset(name) => env.overrides.set(&name, None)?,
+            Command::Clear => {
+                // A new session, the old one stays on disk as it was
+                let system_prompt = session
+                    .messages
+                    .first()
+                    .filter(|x| matches!(x, ChatCompletionRequestMessage::System(_)))
+                    .cloned();
+                *session = env.new_session();
+                env.usage = Usage::default();
+                session.messages.extend(system_prompt);
+            }
+            Command::Save => {
+                session.save(&env.usage)?;
+                println!("Saved session {}", session.id);
+            }
+            Command::System(None) => match session.messages.first() {
+                Some(message @ ChatCompletionRequestMessage::System(_)) => {
+                    println!("{}", message_text(message))
+                }
+                _ => println!("There is no system prompt"),
+            },
+            Command::System(Some(text)) => {
+                let message = ChatCompletionRequestSystemMessageArgs::default()
+                    .content(text)
+                    .build()?
+                    .into();
+                match session.messages.first_mut() {
+                    Some(first @ ChatCompletionRequestMessage::System(_)) => *first = message,
+                    _ => session.messages.insert(0, message),
+                }
+            }
+            Command::History => {
+                for message in &session.messages {
+                    println!("{}: {}", message_role(message), message_text(message));
+                }
+            }
+            Command::Usage if env.usage.is_empty() => println!("No requests yet"),
+            Command::Usage => env.usage.print(&env.conf.llm.prices),
+            Command::Retry => return Ok(Action::Send(session.take_retry()?)),
+            Command::Help => {
+                for (name, arguments, description) in COMMANDS {
+                    println!("{:<18} {}", format!("{name} {arguments}"), description);
+                }
+                println!("A message that starts with a / is sent with // in front of it");
+            }
+            Command::Quit => return Ok(Action::Quit),
+            Command::Message(message) => return Ok(Action::Send(message)),
+        }
+        Ok(Action::Continue)
+    }
+}
+
+// Completes the command names for rustyline
+pub struct CommandCompleter;
+
+impl Completer for CommandCompleter {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let prefix = &line[..pos];
+        // Only the command name itself is completed, not the arguments
+        if !prefix.starts_with('/') || prefix.contains(char::is_whitespace) {
+            return Ok((0, vec![]));
+        }
+        let candidates = COMMANDS
+            .iter()
+            .map(|(name, _, _)| name)
+            .filter(|name| name.starts_with(prefix))
+            .map(|name| name.to_string())
+            .collect();
+        Ok((0, candidates))
+    }
+}
+
+impl Hinter for CommandCompleter {
+    type Hint = String;
+}
+
+impl Highlighter for CommandCompleter {}
+
+impl Validator for CommandCompleter {}
+
+impl Helper for CommandCompleter {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Option<Command> {
+        Command::parse(line).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(None, parse("what's for dinner?"));
+        assert_eq!(Some(Command::Tools), parse("  /tools  "));
+        assert_eq!(Some(Command::Model(None)), parse("/model"));
+        assert_eq!(Some(Command::Model(None)), parse("/model   "));
+        assert_eq!(
+            Some(Command::Model(Some(String::from("gpt-4o-mini")))),
+            parse("/model  gpt-4o-mini ")
+        );
+        assert_eq!(
+            Some(Command::Set(Some(String::from("stop [\"a\", \"b\"]")))),
+            parse("/set stop [\"a\", \"b\"]")
+        );
+        assert!(Command::parse("/unset").is_err());
+        assert_eq!(Some(Command::Quit), parse("/quit"));
+        assert_eq!(Some(Command::Quit), parse("/exit"));
+        assert!(Command::parse("/etc/hosts looks wrong").is_err());
+        assert_eq!(
+            Some(Command::Message(String::from("/etc/hosts looks wrong"))),
+            parse("//etc/hosts looks wrong")
+        );
+    }
+}
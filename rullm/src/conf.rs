@@ -1,24 +1,536 @@
 use std::{
     collections::HashMap,
+    fs,
     path::PathBuf,
+    process::{Command, Stdio},
 };
 
-use anyhow::anyhow;
+use anyhow::{Context as _, anyhow, bail};
 use config::{Config, File};
-use serde::Deserialize;
+use rmcp::serde_json::{self, Value};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tracing::warn;
 
 #[derive(Deserialize, Debug)]
 pub struct Conf {
+    #[serde(default)]
+    pub servers: HashMap<String, ServerConfig>,
+    // Deprecated, superseded by `servers`
+    #[serde(default)]
     pub executables: HashMap<String, String>,
+    #[serde(default)]
     pub environment: HashMap<String, String>,
     pub llm: LLMConfig,
+    #[serde(default)]
+    pub tools: ToolsConfig,
+    #[serde(default)]
+    pub prompt: PromptConfig,
+    #[serde(default)]
+    pub context: ContextConfig,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct LLMConfig {
+    // Which API `base_url` speaks, OpenAI's by default
+    pub provider: Option<LLMProvider>,
+    // Can be left out for servers that don't need one
+    #[serde(default)]
     pub api_key: String,
+    // Instead of `api_key`, so that it doesn't have to be written here. The key is the first line
+    // of the output of the command (e.g. `pass show openai`), the content of the file or the
+    // value of the environment variable
+    pub api_key_cmd: Option<String>,
+    pub api_key_file: Option<PathBuf>,
+    pub api_key_env: Option<String>,
+    pub base_url: Option<String>,
+    pub model: Option<String>,
+    #[serde(flatten)]
+    pub sampling: Sampling,
+    // Print the answer as it is being generated, on by default
+    pub stream: Option<bool>,
+    // How many times the model may call tools before it has to answer, 5 by default and at
+    // least 1
+    pub max_tool_iterations: Option<u32>,
+    // Whether the model accepts images, off by default
+    pub vision: Option<bool>,
+    // What to do with images returned by tools when the model doesn't accept them
+    pub image_fallback: Option<ImageFallback>,
+    // Describe the tools in the system prompt and pick the calls out of the answer, for models
+    // that don't support tools. Only for Ollama, which also switches to it on its own when the
+    // server says that the model can't use tools
+    pub tool_fallback: Option<bool>,
+    // How long to wait for the model, 120 seconds by default. When streaming, that's the
+    // longest pause allowed between two pieces of the answer
+    pub timeout_secs: Option<u64>,
+    // Keyed by the model name, for estimating the cost in `/usage`
+    #[serde(default)]
+    pub prices: HashMap<String, Price>,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    // The profile used unless `--profile` says otherwise. Without one the settings above are
+    // used as they are
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+}
+
+// `[llm.profiles.<name>]`, for switching between models and providers with `/profile`. What
+// isn't set is taken from `[llm]`, except that a profile with a different provider doesn't
+// inherit the address, the key or the model of the other one
+#[derive(Deserialize, Debug, Clone)]
+pub struct Profile {
+    pub provider: Option<LLMProvider>,
+    pub api_key: Option<String>,
+    pub api_key_cmd: Option<String>,
+    pub api_key_file: Option<PathBuf>,
+    pub api_key_env: Option<String>,
     pub base_url: Option<String>,
     pub model: Option<String>,
+    #[serde(flatten)]
+    pub sampling: Sampling,
+}
+
+impl LLMConfig {
+    // The settings of `[llm]` as they are, with the API key read from where it's kept
+    pub fn without_profile(&self) -> anyhow::Result<LLMConfig> {
+        Ok(LLMConfig {
+            api_key: self.read_api_key()?,
+            ..self.clone()
+        })
+    }
+
+    // The settings of the given profile on top of the rest of `[llm]`. The key is read only
+    // now, so that a profile that isn't used can't get in the way
+    pub fn with_profile(&self, name: &str) -> anyhow::Result<LLMConfig> {
+        let Some(profile) = self.profiles.get(name) else {
+            let mut names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+            names.sort();
+            bail!(
+                "Unknown profile '{name}', the profiles are: {}",
+                names.join(", ")
+            )
+        };
+        let provider = profile.provider.or(self.provider);
+        let inherit = provider.unwrap_or_default() == self.provider.unwrap_or_default();
+        let inherited = |own: &Option<String>, other: &Option<String>| {
+            own.clone().or(other.clone().filter(|_| inherit))
+        };
+        let secret = Secret {
+            cmd: profile.api_key_cmd.clone(),
+            file: profile.api_key_file.clone(),
+            env: profile.api_key_env.clone(),
+        };
+        let what = format!("the API key of profile '{name}'");
+        let api_key = match secret.read(&what, profile.api_key.is_some())? {
+            Some(key) => key,
+            None => match &profile.api_key {
+                Some(key) => key.clone(),
+                None if inherit => self.read_api_key()?,
+                None => String::new(),
+            },
+        };
+        Ok(LLMConfig {
+            provider,
+            api_key,
+            base_url: inherited(&profile.base_url, &self.base_url),
+            model: inherited(&profile.model, &self.model),
+            sampling: profile.sampling.or(&self.sampling),
+            ..self.clone()
+        })
+    }
+
+    fn read_api_key(&self) -> anyhow::Result<String> {
+        let secret = Secret {
+            cmd: self.api_key_cmd.clone(),
+            file: self.api_key_file.clone(),
+            env: self.api_key_env.clone(),
+        };
+        let key = secret.read("the API key", !self.api_key.is_empty())?;
+        Ok(key.unwrap_or(self.api_key.clone()))
+    }
+}
+
+// How the answers are generated, left to the provider when not set. They can be changed for the
+// rest of the session with `/set`. Not every provider knows every setting, the ones it doesn't
+// know are left out of its requests
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Sampling {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    // The longest answer allowed. Anthropic requires one, 4096 is used there by default
+    pub max_tokens: Option<u32>,
+    pub seed: Option<i64>,
+    // Sequences that end the answer when the model writes them
+    pub stop: Option<Vec<String>>,
+    pub parallel_tool_calls: Option<bool>,
+    // "auto", "none", "required" or the name of a tool that has to be called
+    pub tool_choice: Option<String>,
+    // For the reasoning models of OpenAI
+    pub reasoning_effort: Option<ReasoningEffort>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
+}
+
+// The names for `/set` and `/unset`
+pub const SAMPLING_SETTINGS: &[&str] = &[
+    "temperature",
+    "top_p",
+    "max_tokens",
+    "seed",
+    "stop",
+    "parallel_tool_calls",
+    "tool_choice",
+    "reasoning_effort",
+];
+
+impl Sampling {
+    // These settings, with the ones of `other` where these aren't set
+    pub fn or(&self, other: &Sampling) -> Sampling {
+        Sampling {
+            temperature: self.temperature.or(other.temperature),
+            top_p: self.top_p.or(other.top_p),
+            max_tokens: self.max_tokens.or(other.max_tokens),
+            seed: self.seed.or(other.seed),
+            stop: self.stop.clone().or(other.stop.clone()),
+            parallel_tool_calls: self.parallel_tool_calls.or(other.parallel_tool_calls),
+            tool_choice: self.tool_choice.clone().or(other.tool_choice.clone()),
+            reasoning_effort: self.reasoning_effort.or(other.reasoning_effort),
+        }
+    }
+
+    // Sets a value typed in the REPL, None unsets it
+    pub fn set(&mut self, name: &str, value: Option<&str>) -> anyhow::Result<()> {
+        let value = value.map(|x| setting_value(name, x)).unwrap_or(Value::Null);
+        match name {
+            "temperature" => self.temperature = typed(name, value)?,
+            "top_p" => self.top_p = typed(name, value)?,
+            "max_tokens" => self.max_tokens = typed(name, value)?,
+            "seed" => self.seed = typed(name, value)?,
+            "stop" => self.stop = typed(name, value)?,
+            "parallel_tool_calls" => self.parallel_tool_calls = typed(name, value)?,
+            "tool_choice" => self.tool_choice = typed(name, value)?,
+            "reasoning_effort" => self.reasoning_effort = typed(name, value)?,
+            _ => bail!(
+                "Unknown setting '{name}', the settings are: {}",
+                SAMPLING_SETTINGS.join(", ")
+            ),
+        }
+        Ok(())
+    }
+
+    // The settings that are set, for showing them
+    pub fn describe(&self) -> Vec<(&'static str, String)> {
+        let values = [
+            self.temperature.map(|x| x.to_string()),
+            self.top_p.map(|x| x.to_string()),
+            self.max_tokens.map(|x| x.to_string()),
+            self.seed.map(|x| x.to_string()),
+            self.stop.as_ref().map(|x| format!("{x:?}")),
+            self.parallel_tool_calls.map(|x| x.to_string()),
+            self.tool_choice.clone(),
+            self.reasoning_effort
+                .map(|x| format!("{x:?}").to_lowercase()),
+        ];
+        SAMPLING_SETTINGS
+            .iter()
+            .zip(values)
+            .filter_map(|(name, value)| Some((*name, value?)))
+            .collect()
+    }
+}
+
+// Strings don't need quotes in the REPL. Several stop sequences are given as a JSON array
+fn setting_value(name: &str, value: &str) -> Value {
+    match name {
+        "stop" if !value.starts_with('[') => Value::Array(vec![Value::from(value)]),
+        "tool_choice" | "reasoning_effort" => Value::from(value),
+        _ => serde_json::from_str(value).unwrap_or(Value::from(value)),
+    }
+}
+
+fn typed<T: DeserializeOwned>(name: &str, value: Value) -> anyhow::Result<T> {
+    serde_json::from_value(value).with_context(|| format!("Invalid value for {name}"))
+}
+
+// `[llm.retry]`, for rate limits, server errors and connection problems
+#[derive(Deserialize, Debug, Clone)]
+pub struct RetryConfig {
+    // Including the first one, 1 turns the retries off
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+    // Doubled for every retry, unless the server says how long to wait
+    #[serde(default = "default_initial_delay_ms")]
+    pub initial_delay_ms: u64,
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            attempts: default_attempts(),
+            initial_delay_ms: default_initial_delay_ms(),
+            max_delay_ms: default_max_delay_ms(),
+        }
+    }
+}
+
+fn default_attempts() -> u32 {
+    4
+}
+
+fn default_initial_delay_ms() -> u64 {
+    500
+}
+
+fn default_max_delay_ms() -> u64 {
+    30_000
+}
+
+// Prices per million tokens, in whatever currency the provider bills in
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct Price {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LLMProvider {
+    // The chat completions API, which many other providers and local servers speak too
+    #[default]
+    OpenAI,
+    // The Messages API
+    Anthropic,
+    // The native API of Ollama, `base_url` is the address of the server without `/v1`
+    Ollama,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFallback {
+    // Tell the model that there was an image that it can't see
+    #[default]
+    Placeholder,
+    // Save the image to the data directory and tell the model where it is
+    Save,
+}
+
+// A `[servers.<name>]` table, one for each MCP server that is spawned
+#[derive(Deserialize, Debug)]
+pub struct ServerConfig {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    // Only passed to this server, on top of the environment of rullm itself. A value can also
+    // be read like an API key, e.g. `TOKEN = { cmd = "pass show mealie" }`
+    #[serde(default, rename = "env")]
+    env_values: HashMap<String, EnvValue>,
+    // The values of `env` once the secrets have been read by `Conf::build`
+    #[serde(skip)]
+    pub env: HashMap<String, String>,
+    pub cwd: Option<PathBuf>,
+    // For starting the server and for each tool call, 60 seconds by default
+    pub timeout_secs: Option<u64>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ToolsConfig {
+    // Put between the server name and the tool name, e.g. `mealie__add_to_list`. Only letters,
+    // digits, `_` and `-`, which are all that the APIs accept in a tool name
+    #[serde(default = "default_separator")]
+    pub separator: String,
+    // How many tool calls of a single answer are run at the same time
+    #[serde(default = "default_max_parallel")]
+    pub max_parallel: usize,
+    // Keyed by a tool name, like `mealie__add_to_list`, or a server name for all of its tools
+    #[serde(default)]
+    pub policy: HashMap<String, ToolPolicy>,
+    // For the tools that aren't mentioned in `policy`
+    #[serde(default)]
+    pub default_policy: ToolPolicy,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolPolicy {
+    #[default]
+    Allow,
+    // Ask the user before every call
+    Ask,
+    Deny,
+}
+
+impl ToolsConfig {
+    pub fn policy_for(&self, tool: &str) -> ToolPolicy {
+        let server = tool.split_once(&self.separator).map(|(server, _)| server);
+        self.policy
+            .get(tool)
+            .or(server.and_then(|x| self.policy.get(x)))
+            .copied()
+            .unwrap_or(self.default_policy)
+    }
+}
+
+impl Default for ToolsConfig {
+    fn default() -> Self {
+        ToolsConfig {
+            separator: default_separator(),
+            max_parallel: default_max_parallel(),
+            policy: HashMap::new(),
+            default_policy: ToolPolicy::default(),
+        }
+    }
+}
+
+fn default_separator() -> String {
+    String::from("__")
+}
+
+fn default_max_parallel() -> usize {
+    4
+}
+
+// The `[prompt]` section. The system prompt is a template, see `prompt.rs` for the variables
+#[derive(Deserialize, Debug, Default)]
+pub struct PromptConfig {
+    #[serde(flatten)]
+    pub default: SystemPrompt,
+    // Alternative system prompts, picked with `--persona <name>`
+    #[serde(default)]
+    pub personas: HashMap<String, SystemPrompt>,
+}
+
+// Either the template itself or a file that contains it, not both
+#[derive(Deserialize, Debug, Default)]
+pub struct SystemPrompt {
+    pub system: Option<String>,
+    pub system_file: Option<PathBuf>,
+}
+
+impl SystemPrompt {
+    pub fn template(&self) -> anyhow::Result<Option<String>> {
+        match (&self.system, &self.system_file) {
+            (Some(_), Some(_)) => bail!("Only one of `system` and `system_file` can be set"),
+            (Some(system), None) => Ok(Some(system.clone())),
+            (None, Some(path)) => std::fs::read_to_string(path)
+                .map(Some)
+                .with_context(|| format!("Failed to read the system prompt {}", path.display())),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+// How the conversation is kept within the context window of the model
+#[derive(Deserialize, Debug)]
+pub struct ContextConfig {
+    // An estimate, including the tool definitions. Older turns go when it's exceeded
+    #[serde(default = "default_max_tokens")]
+    pub max_tokens: usize,
+    #[serde(default)]
+    pub strategy: ContextStrategy,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ContextStrategy {
+    // Drop the oldest turns
+    #[default]
+    Truncate,
+    // Replace the oldest turns with a summary written by the model
+    Summarize,
+}
+
+impl Default for ContextConfig {
+    fn default() -> Self {
+        ContextConfig {
+            max_tokens: default_max_tokens(),
+            strategy: ContextStrategy::default(),
+        }
+    }
+}
+
+fn default_max_tokens() -> usize {
+    100_000
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum EnvValue {
+    Plain(String),
+    Secret(Secret),
+}
+
+// Where a secret is read from, only one of these can be set
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Secret {
+    // Run with `sh -c`, the first line of the output is used
+    pub cmd: Option<String>,
+    // The whole file without the surrounding whitespace, `~/` is the home directory
+    pub file: Option<PathBuf>,
+    pub env: Option<String>,
+}
+
+impl Secret {
+    // None when the secret isn't read from anywhere. `given` tells whether the value was also
+    // written in the configuration as it is
+    fn read(&self, what: &str, given: bool) -> anyhow::Result<Option<String>> {
+        let sources = [self.cmd.is_some(), self.file.is_some(), self.env.is_some()];
+        match sources.iter().filter(|x| **x).count() + usize::from(given) {
+            0 => return Ok(None),
+            1 => {}
+            _ => bail!("There is more than one value for {what}"),
+        }
+        let value = if let Some(cmd) = &self.cmd {
+            // The command may have to ask for a passphrase, so only the output is captured
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(cmd)
+                .stdin(Stdio::inherit())
+                .stderr(Stdio::inherit())
+                .output()
+                .with_context(|| format!("Failed to run the command for {what}"))?;
+            if !output.status.success() {
+                bail!("The command for {what} failed with {}", output.status)
+            }
+            let output = String::from_utf8(output.stdout).with_context(|| {
+                format!("The command for {what} printed something else than text")
+            })?;
+            output.lines().next().unwrap_or_default().to_string()
+        } else if let Some(file) = &self.file {
+            let file = match (file.strip_prefix("~"), dirs_next::home_dir()) {
+                (Ok(rest), Some(home)) => home.join(rest),
+                _ => file.clone(),
+            };
+            fs::read_to_string(&file)
+                .with_context(|| format!("Failed to read {what} from {}", file.display()))?
+                .trim()
+                .to_string()
+        } else if let Some(name) = &self.env {
+            std::env::var(name).with_context(|| {
+                format!("Failed to read {what} from the environment variable {name}")
+            })?
+        } else {
+            return Ok(None);
+        };
+        if value.is_empty() {
+            bail!("The value read for {what} is empty")
+        }
+        Ok(Some(value))
+    }
 }
 
 impl Conf {
@@ -32,7 +544,227 @@ impl Conf {
             .or(config_file)
             .ok_or(anyhow!("Configuration file missing"))?;
         let settings = Config::builder().add_source(File::from(path)).build()?;
-        let conf = settings.try_deserialize::<Conf>()?;
+        let mut conf = settings.try_deserialize::<Conf>()?;
+        conf.migrate_executables();
+        conf.check()?;
+        conf.read_secrets()?;
         Ok(conf)
     }
+
+    // The values that deserialize fine but make no sense
+    fn check(&self) -> anyhow::Result<()> {
+        if self.llm.max_tool_iterations == Some(0) {
+            bail!("max_tool_iterations has to be at least 1, otherwise the model is never asked")
+        }
+        Ok(())
+    }
+
+    // The API keys are read when the profile they are for is used
+    fn read_secrets(&mut self) -> anyhow::Result<()> {
+        // The secrets of the servers that won't be started aren't needed
+        for (name, server) in self.servers.iter_mut().filter(|(_, x)| x.enabled) {
+            for (key, value) in server.env_values.drain() {
+                let value = match value {
+                    EnvValue::Plain(value) => value,
+                    EnvValue::Secret(secret) => {
+                        let what = format!("{key} of server '{name}'");
+                        secret.read(&what, false)?.ok_or(anyhow!(
+                            "There is no value for {what}, give one of `cmd`, `file` or `env`"
+                        ))?
+                    }
+                };
+                server.env.insert(key, value);
+            }
+        }
+        Ok(())
+    }
+
+    // Turn the old `executables` and `environment` tables into `servers` entries, so that old
+    // configuration files keep on working
+    fn migrate_executables(&mut self) {
+        if self.executables.is_empty() {
+            return;
+        }
+        warn!("`executables` and `environment` are deprecated, use `[servers.<name>]` instead");
+        for (name, command) in self.executables.drain() {
+            self.servers.entry(name).or_insert(ServerConfig {
+                command,
+                args: vec![],
+                env_values: HashMap::new(),
+                env: self.environment.clone(),
+                cwd: None,
+                timeout_secs: None,
+                enabled: true,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::FileFormat;
+
+    #[test]
+    fn test_profile_inherits_from_the_same_provider_only() {
+        let conf = r#"
+            [llm]
+            api_key = "sk-openai"
+            base_url = "http://proxy/v1"
+            model = "gpt-4o"
+            temperature = 0.5
+
+            [llm.profiles.mini]
+            model = "gpt-4o-mini"
+
+            [llm.profiles.claude]
+            provider = "anthropic"
+            api_key = "sk-ant"
+            max_tokens = 1000
+        "#;
+        let conf: Conf = Config::builder()
+            .add_source(File::from_str(conf, FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        let mini = conf.llm.with_profile("mini").unwrap();
+        assert_eq!(Some("gpt-4o-mini"), mini.model.as_deref());
+        assert_eq!(Some("http://proxy/v1"), mini.base_url.as_deref());
+        assert_eq!("sk-openai", mini.api_key);
+        let claude = conf.llm.with_profile("claude").unwrap();
+        assert_eq!(Some(LLMProvider::Anthropic), claude.provider);
+        assert_eq!((None, None), (claude.model, claude.base_url));
+        assert_eq!("sk-ant", claude.api_key);
+        assert_eq!(
+            (Some(0.5), Some(1000)),
+            (claude.sampling.temperature, claude.sampling.max_tokens)
+        );
+        assert!(conf.llm.with_profile("gpt-5").is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let conf = |llm: &str| -> Conf {
+            Config::builder()
+                .add_source(File::from_str(&format!("[llm]\n{llm}"), FileFormat::Toml))
+                .build()
+                .unwrap()
+                .try_deserialize()
+                .unwrap()
+        };
+        assert!(conf("").check().is_ok());
+        assert!(conf("max_tool_iterations = 1").check().is_ok());
+        assert!(conf("max_tool_iterations = 0").check().is_err());
+    }
+
+    #[test]
+    fn test_tool_policy_precedence() {
+        let conf = r#"
+            [llm]
+
+            [tools]
+            default_policy = "ask"
+
+            [tools.policy]
+            mealie = "allow"
+            mealie__delete_recipe = "deny"
+            shell__ls = "allow"
+        "#;
+        let conf: Conf = Config::builder()
+            .add_source(File::from_str(conf, FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        let tools = &conf.tools;
+        // The tool wins over its server, and the server over the default
+        assert_eq!(ToolPolicy::Deny, tools.policy_for("mealie__delete_recipe"));
+        assert_eq!(ToolPolicy::Allow, tools.policy_for("mealie__add_to_list"));
+        assert_eq!(ToolPolicy::Allow, tools.policy_for("shell__ls"));
+        assert_eq!(ToolPolicy::Ask, tools.policy_for("shell__rm"));
+        // Only the part before the separator is the server
+        assert_eq!(ToolPolicy::Ask, tools.policy_for("mealie_add"));
+    }
+
+    #[test]
+    fn test_set_sampling() {
+        let mut sampling = Sampling::default();
+        sampling.set("temperature", Some("0.3")).unwrap();
+        sampling.set("stop", Some("END")).unwrap();
+        sampling.set("reasoning_effort", Some("high")).unwrap();
+        sampling.set("tool_choice", Some("notes__add")).unwrap();
+        assert_eq!(Some(0.3), sampling.temperature);
+        assert_eq!(Some(vec![String::from("END")]), sampling.stop);
+        assert_eq!(Some(ReasoningEffort::High), sampling.reasoning_effort);
+        sampling.set("stop", Some("[\"a\", \"b\"]")).unwrap();
+        assert_eq!(2, sampling.stop.as_ref().unwrap().len());
+        sampling.set("temperature", None).unwrap();
+        assert_eq!(None, sampling.temperature);
+        assert!(sampling.set("max_tokens", Some("-1")).is_err());
+        assert!(sampling.set("reasoning_effort", Some("extreme")).is_err());
+        assert!(sampling.set("verbosity", Some("1")).is_err());
+    }
+
+    #[test]
+    fn test_read_secrets() {
+        let file = std::env::temp_dir().join(format!("rullm-secret-{}", std::process::id()));
+        fs::write(&file, "from-file\n").unwrap();
+        let conf = format!(
+            r#"
+            [llm]
+            api_key_cmd = "printf 'from-cmd\nsecond line'"
+
+            [llm.profiles.local]
+            api_key_file = "{}"
+
+            [llm.profiles.broken]
+            api_key_cmd = "exit 1"
+
+            [servers.mealie]
+            command = "mealie"
+            env = {{ MEALIE_URL = "http://mealie", MEALIE_TOKEN = {{ env = "PATH" }} }}
+
+            [servers.off]
+            command = "off"
+            enabled = false
+            env = {{ TOKEN = {{ cmd = "exit 1" }} }}
+            "#,
+            file.display()
+        );
+        let mut conf: Conf = Config::builder()
+            .add_source(File::from_str(&conf, FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        // The profiles that aren't used don't matter
+        conf.read_secrets().unwrap();
+        assert_eq!("from-cmd", conf.llm.without_profile().unwrap().api_key);
+        assert_eq!("from-file", conf.llm.with_profile("local").unwrap().api_key);
+        fs::remove_file(&file).unwrap();
+        assert!(conf.llm.with_profile("broken").is_err());
+        let env = &conf.servers["mealie"].env;
+        assert_eq!("http://mealie", env["MEALIE_URL"]);
+        assert_eq!(std::env::var("PATH").unwrap(), env["MEALIE_TOKEN"]);
+
+        let secret = Secret {
+            cmd: Some(String::from("exit 1")),
+            ..Secret::default()
+        };
+        assert!(secret.read("the key", false).is_err());
+        assert!(secret.read("the key", true).is_err());
+        assert_eq!(None, Secret::default().read("the key", true).unwrap());
+        // An empty table gives no value at all
+        let mut conf: Conf = Config::builder()
+            .add_source(File::from_str(
+                "llm = {}\nservers.notes = { command = \"notes\", env = { TOKEN = {} } }",
+                FileFormat::Toml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        assert!(conf.read_secrets().is_err());
+    }
 }
@@ -0,0 +1,218 @@
+use async_openai::types::{
+    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
+    ChatCompletionRequestUserMessageArgs,
+};
+use rmcp::serde_json::{self, Value};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{
+    conf::ContextStrategy,
+    env::Env,
+    message::{is_turn_start, message_role, message_text},
+};
+
+// Images are billed by their size, not by the length of the data URL
+const IMAGE_TOKENS: usize = 1000;
+// The role and the delimiters of a message
+const MESSAGE_TOKENS: usize = 4;
+
+const SUMMARY_PROMPT: &str = "Summarize the conversation below for yourself, it will replace \
+    the conversation in your history. Keep the facts, the decisions and the results of the \
+    tool calls that may matter later. Answer only with the summary.";
+
+// A rough estimate of four characters per token, which is close enough for English text
+pub fn estimate_tokens<T: Serialize>(value: &T) -> usize {
+    fn walk(value: &Value) -> usize {
+        match value {
+            Value::String(x) if x.starts_with("data:") => IMAGE_TOKENS,
+            Value::String(x) => x.chars().count().div_ceil(4),
+            Value::Array(xs) => xs.iter().map(walk).sum(),
+            Value::Object(xs) => xs.iter().map(|(k, v)| k.len().div_ceil(4) + walk(v)).sum(),
+            _ => 1,
+        }
+    }
+    serde_json::to_value(value).map(|x| walk(&x)).unwrap_or(0)
+}
+
+fn message_tokens(message: &ChatCompletionRequestMessage) -> usize {
+    MESSAGE_TOKENS + estimate_tokens(message)
+}
+
+// Makes room in the history when it doesn't fit in `[context] max_tokens`. Only whole turns
+// before `current` are removed, so that tool calls stay together with their results and the
+// turn in progress is left alone. The system prompt is always kept
+//
+// Returns how many messages were removed from before `current`
+pub async fn fit(
+    env: &mut Env,
+    messages: &mut Vec<ChatCompletionRequestMessage>,
+    current: usize,
+) -> anyhow::Result<usize> {
+    let conf = &env.conf.context;
+    let tokens: Vec<usize> = messages.iter().map(message_tokens).collect();
+    let total = estimate_tokens(&env.llm.tools()) + tokens.iter().sum::<usize>();
+    if total <= conf.max_tokens {
+        return Ok(0);
+    }
+    let first = match messages.first() {
+        Some(ChatCompletionRequestMessage::System(_)) => 1,
+        _ => 0,
+    };
+    // Summarizing takes a request of its own, so it makes more room at once
+    let target = match conf.strategy {
+        ContextStrategy::Truncate => conf.max_tokens,
+        ContextStrategy::Summarize => conf.max_tokens / 2,
+    };
+    let end = droppable_turns(messages, &tokens, first, current, total - target);
+    if end == first {
+        warn!(
+            total,
+            "Over the context limit, but there are no older turns"
+        );
+        return Ok(0);
+    }
+    let removed = end - first;
+    match conf.strategy {
+        ContextStrategy::Truncate => {
+            messages.drain(first..end);
+            info!(removed, total, "Dropped old turns to fit the context");
+            Ok(removed)
+        }
+        ContextStrategy::Summarize => {
+            // The history is only changed once the summary is there, in case the turn is
+            // cancelled while waiting for it
+            let summary = summarize(env, &messages[first..end]).await?;
+            messages.splice(first..end, [summary]);
+            info!(removed, total, "Summarized old turns to fit the context");
+            Ok(removed - 1)
+        }
+    }
+}
+
+// The end of the range of whole turns, starting from `first`, that add up to at least `excess`
+// tokens. Never goes past `current`
+fn droppable_turns(
+    messages: &[ChatCompletionRequestMessage],
+    tokens: &[usize],
+    first: usize,
+    current: usize,
+    excess: usize,
+) -> usize {
+    let mut end = first;
+    let mut freed = 0;
+    while freed < excess && end < current {
+        let next = messages[end + 1..current]
+            .iter()
+            .position(is_turn_start)
+            .map_or(current, |x| end + 1 + x);
+        freed += tokens[end..next].iter().sum::<usize>();
+        end = next;
+    }
+    end
+}
+
+async fn summarize(
+    env: &mut Env,
+    dropped: &[ChatCompletionRequestMessage],
+) -> anyhow::Result<ChatCompletionRequestMessage> {
+    let transcript = dropped
+        .iter()
+        .map(|x| format!("{}: {}", message_role(x), message_text(x)))
+        .collect::<Vec<String>>()
+        .join("\n\n");
+    let request = [
+        ChatCompletionRequestSystemMessageArgs::default()
+            .content(SUMMARY_PROMPT)
+            .build()?
+            .into(),
+        ChatCompletionRequestUserMessageArgs::default()
+            .content(transcript)
+            .build()?
+            .into(),
+    ];
+    let reply = env.llm.complete(&request).await?;
+    env.usage.record(env.llm.model(), reply.usage.as_ref());
+    Ok(ChatCompletionRequestUserMessageArgs::default()
+        .content(format!(
+            "Summary of the earlier conversation:\n{}",
+            reply.content
+        ))
+        .build()?
+        .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::{
+        ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessageArgs,
+        ChatCompletionRequestToolMessageArgs, ChatCompletionToolType, FunctionCall,
+    };
+
+    fn user(text: &str) -> ChatCompletionRequestMessage {
+        ChatCompletionRequestUserMessageArgs::default()
+            .content(text)
+            .build()
+            .unwrap()
+            .into()
+    }
+
+    fn assistant(tool_call: Option<&str>) -> ChatCompletionRequestMessage {
+        let mut message = ChatCompletionRequestAssistantMessageArgs::default();
+        if let Some(id) = tool_call {
+            message.tool_calls(vec![ChatCompletionMessageToolCall {
+                id: String::from(id),
+                r#type: ChatCompletionToolType::Function,
+                function: FunctionCall {
+                    name: String::from("notes__add"),
+                    arguments: String::from("{}"),
+                },
+            }]);
+        } else {
+            message.content("ok");
+        }
+        message.build().unwrap().into()
+    }
+
+    fn tool(id: &str) -> ChatCompletionRequestMessage {
+        ChatCompletionRequestToolMessageArgs::default()
+            .tool_call_id(id)
+            .content("done")
+            .build()
+            .unwrap()
+            .into()
+    }
+
+    #[test]
+    fn test_drop_whole_turns() {
+        let system = ChatCompletionRequestSystemMessageArgs::default()
+            .content("system")
+            .build()
+            .unwrap()
+            .into();
+        let messages = vec![
+            system,
+            user("first"),
+            assistant(Some("a")),
+            tool("a"),
+            assistant(None),
+            user("second"),
+            assistant(None),
+            user("third"),
+        ];
+        let tokens = vec![10; messages.len()];
+        // A single token too many takes the first turn with its tool call and result
+        assert_eq!(5, droppable_turns(&messages, &tokens, 1, 7, 1));
+        assert_eq!(7, droppable_turns(&messages, &tokens, 1, 7, 41));
+        // The current turn is never dropped
+        assert_eq!(7, droppable_turns(&messages, &tokens, 1, 7, 1000));
+    }
+
+    #[test]
+    fn test_estimate_image_tokens() {
+        let image = Value::String(format!("data:image/png;base64,{}", "A".repeat(100_000)));
+        assert_eq!(IMAGE_TOKENS, estimate_tokens(&image));
+        assert_eq!(3, estimate_tokens(&"hello world"));
+    }
+}
@@ -1,20 +1,171 @@
-use crate::{args::Args, conf::Conf, mcp::MCP, openai::OpenAIClient};
+use anyhow::{Context as _, bail};
+
+use crate::{
+    approval::Approvals,
+    args::Args,
+    conf::{Conf, LLMProvider, Sampling},
+    mcp::MCP,
+    provider::{self, Provider},
+    session::Session,
+    usage::Usage,
+};
 
 pub struct Env {
-    pub openai_client: OpenAIClient,
+    pub llm: Box<dyn Provider>,
+    // The profile from `[llm.profiles]` that `llm` was built from, if any
+    pub profile: Option<String>,
+    // The API that `llm` speaks
+    pub provider: LLMProvider,
     pub mcp: MCP,
     pub conf: Conf,
+    pub approvals: Approvals,
+    // Picks the system prompt of new sessions from `[prompt.personas]`
+    pub persona: Option<String>,
+    pub usage: Usage,
+    // The sampling settings changed with `/set`, they stay when the profile is switched
+    pub overrides: Sampling,
 }
 
 impl Env {
     pub async fn build(args: Args) -> anyhow::Result<Env> {
         let conf = Conf::build(args.conf_file)?;
+        Env::new(conf, args.profile, args.persona).await
+    }
+
+    pub async fn new(
+        conf: Conf,
+        profile: Option<String>,
+        persona: Option<String>,
+    ) -> anyhow::Result<Env> {
+        if let Some(persona) = &persona
+            && !conf.prompt.personas.contains_key(persona)
+        {
+            bail!("Unknown persona '{persona}'")
+        }
         let mcp = MCP::build(&conf).await?;
-        let openai_client = OpenAIClient::build(&conf, &mcp).await?;
+        let profile = profile.or(conf.llm.profile.clone());
+        let llm_conf = match &profile {
+            Some(name) => conf.llm.with_profile(name)?,
+            None => conf.llm.without_profile()?,
+        };
+        let llm = provider::build(&llm_conf, &mcp)?;
         Ok(Env {
-            openai_client,
+            llm,
+            profile,
+            provider: llm_conf.provider.unwrap_or_default(),
             mcp,
             conf,
+            approvals: Approvals::new(),
+            persona,
+            usage: Usage::default(),
+            overrides: Sampling::default(),
         })
     }
+
+    // The history is kept by the caller, only the client is replaced
+    pub fn switch_profile(&mut self, name: &str) -> anyhow::Result<()> {
+        let llm_conf = self.conf.llm.with_profile(name)?;
+        self.llm = provider::build(&llm_conf, &self.mcp)?;
+        self.profile = Some(String::from(name));
+        self.provider = llm_conf.provider.unwrap_or_default();
+        Ok(())
+    }
+
+    // A session for the model in use
+    pub fn new_session(&self) -> Session {
+        Session::new(self.llm.model(), self.profile.as_deref(), self.provider)
+    }
+
+    // Goes back to the profile, the model and the usage of a resumed session. A profile given with
+    // `--profile` wins, and so does its model. The session is then saved with what is used
+    pub fn resume(&mut self, session: &mut Session, profile_given: bool) -> anyhow::Result<()> {
+        if !profile_given {
+            if let Some(profile) = &session.profile
+                && self.profile.as_ref() != Some(profile)
+            {
+                self.switch_profile(profile).context(
+                    "Failed to switch to the profile of the session, pick one with --profile",
+                )?;
+            }
+            // The model of another provider would only be turned down
+            if session.provider == Some(self.provider) {
+                self.llm.set_model(&session.model);
+            }
+        }
+        self.usage = session.usage.clone();
+        session.model = String::from(self.llm.model());
+        session.profile = self.profile.clone();
+        session.provider = Some(self.provider);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{Config, File, FileFormat};
+
+    async fn build_env(profile: Option<&str>) -> Env {
+        let conf = r#"
+            [llm]
+            model = "gpt-4o"
+
+            [llm.profiles.mini]
+            model = "gpt-4o-mini"
+
+            [llm.profiles.claude]
+            provider = "anthropic"
+            model = "claude-sonnet-4-5"
+        "#;
+        let conf: Conf = Config::builder()
+            .add_source(File::from_str(conf, FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        Env::new(conf, profile.map(String::from), None)
+            .await
+            .unwrap()
+    }
+
+    fn current(env: &Env) -> (Option<&str>, LLMProvider, &str) {
+        (env.profile.as_deref(), env.provider, env.llm.model())
+    }
+
+    #[tokio::test]
+    async fn test_resume_with_the_profile_of_the_session() {
+        let claude = || Session::new("claude-opus-4-1", Some("claude"), LLMProvider::Anthropic);
+        let mut env = build_env(None).await;
+        env.resume(&mut claude(), false).unwrap();
+        assert_eq!(
+            (Some("claude"), LLMProvider::Anthropic, "claude-opus-4-1"),
+            current(&env)
+        );
+        // `--profile` wins over the session, and so does the model of that profile
+        let mut env = build_env(Some("mini")).await;
+        let mut session = claude();
+        env.resume(&mut session, true).unwrap();
+        assert_eq!(
+            (Some("mini"), LLMProvider::OpenAI, "gpt-4o-mini"),
+            current(&env)
+        );
+        assert_eq!(
+            (Some("mini"), Some(LLMProvider::OpenAI), "gpt-4o-mini"),
+            (
+                session.profile.as_deref(),
+                session.provider,
+                session.model.as_str()
+            )
+        );
+        let mut env = build_env(None).await;
+        let mut session = Session::new("gpt-4.1", None, LLMProvider::OpenAI);
+        env.resume(&mut session, false).unwrap();
+        assert_eq!((None, LLMProvider::OpenAI, "gpt-4.1"), current(&env));
+        // Without a provider it's not known where the model belongs
+        let mut old = Session::new("claude-opus-4-1", None, LLMProvider::Anthropic);
+        old.provider = None;
+        let mut env = build_env(None).await;
+        env.resume(&mut old, false).unwrap();
+        assert_eq!((None, LLMProvider::OpenAI, "gpt-4o"), current(&env));
+    }
 }
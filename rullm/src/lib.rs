@@ -1,6 +1,17 @@
+pub mod anthropic;
+pub mod approval;
 pub mod args;
 pub mod chat;
+pub mod commands;
 pub mod conf;
+pub mod context;
 pub mod env;
 pub mod mcp;
+pub mod message;
+pub mod ollama;
 pub mod openai;
+pub mod prompt;
+pub mod provider;
+pub mod retry;
+pub mod session;
+pub mod usage;
@@ -1,5 +1,9 @@
 use clap::Parser;
-use rullm::{args::Args, env::Env};
+use rullm::{
+    args::Args,
+    env::Env,
+    session::{self, Session},
+};
 use tracing_subscriber::{Registry, layer::SubscriberExt as _};
 
 #[tokio::main]
@@ -9,6 +13,19 @@ async fn main() -> anyhow::Result<()> {
     tracing::subscriber::set_global_default(subscriber)?;
 
     let args = Args::parse();
-    let env = Env::build(args).await?;
-    rullm::chat::run(env).await
+    if args.list_sessions {
+        return session::print_sessions();
+    }
+    if let Some(prompt) = args.one_shot_prompt()? {
+        let json = args.json;
+        let env = Env::build(args).await?;
+        return rullm::chat::one_shot(env, &prompt, json).await;
+    }
+    let mut session = Session::from_args(&args)?;
+    let profile_given = args.profile.is_some();
+    let mut env = Env::build(args).await?;
+    if let Some(session) = &mut session {
+        env.resume(session, profile_given)?;
+    }
+    rullm::chat::run(env, session).await
 }
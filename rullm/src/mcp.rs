@@ -1,61 +1,224 @@
-use anyhow::{anyhow, bail};
+use std::{collections::BTreeMap, time::Duration};
+
+use anyhow::{Context as _, anyhow, bail};
 use async_openai::types::{
     ChatCompletionTool, ChatCompletionToolArgs, FunctionCall, FunctionObject,
 };
 use rmcp::{
-    RoleClient, ServiceExt,
-    model::{CallToolRequestParam, CallToolResult, JsonObject, Tool},
+    Peer, RoleClient, ServiceExt,
+    model::{
+        CallToolRequest, CallToolRequestParam, CallToolResult, CancelledNotificationParam,
+        ClientRequest, JsonObject, RequestId, ServerResult, Tool,
+    },
     serde_json::{self, Value},
-    service::RunningService,
+    service::{PeerRequestOptions, RunningService},
     transport::TokioChildProcess,
 };
 use tokio::process::Command;
+use tracing::warn;
 
 use crate::conf::Conf;
 
-pub struct MCP {
+// What the OpenAI and Anthropic APIs accept in a function name, `^[a-zA-Z0-9_-]{1,64}$`
+const MAX_NAME_LENGTH: usize = 64;
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+// A single connected MCP server and the tools it advertised at startup
+struct Server {
     client: RunningService<RoleClient, ()>,
+    tools: Vec<Tool>,
+    timeout: Duration,
+}
+
+pub struct MCP {
+    // Keyed by the server name, which is also the prefix of its tools
+    servers: BTreeMap<String, Server>,
+    separator: String,
 }
 
 impl MCP {
     pub async fn build(
         Conf {
-            executables,
-            environment,
+            servers: server_confs,
+            tools,
             ..
         }: &Conf,
     ) -> anyhow::Result<MCP> {
-        // I'm for now only taking one item here
-        // The data format supports multiple but I haven't yet gotten multiple servers, nor have
-        // I figured out how to create multiple clients. The APIs say something about peering
-        // but not familiar enough yet
-        let mcp_client = executables.get("mealie").ok_or(anyhow!("Missing mealie"));
-        let mut cmd = Command::new(mcp_client?);
-        for (key, value) in environment {
-            cmd.env(key, value);
+        let separator = tools.separator.clone();
+        check_separator(&separator)?;
+        let mut servers = BTreeMap::new();
+        for (name, server_conf) in server_confs.iter().filter(|(_, x)| x.enabled) {
+            if name.contains(&separator) {
+                bail!("MCP server name '{name}' contains the tool name separator '{separator}'")
+            }
+            let mut cmd = Command::new(&server_conf.command);
+            cmd.args(&server_conf.args).envs(&server_conf.env);
+            // Ctrl-C in the terminal goes to the whole process group, but it's meant for
+            // cancelling the turn, not for stopping the servers
+            cmd.process_group(0);
+            if let Some(cwd) = &server_conf.cwd {
+                cmd.current_dir(cwd);
+            }
+            let timeout = Duration::from_secs(server_conf.timeout_secs.unwrap_or(60));
+            let transport = TokioChildProcess::new(&mut cmd)?;
+            let client = with_timeout(timeout, ().serve(transport))
+                .await
+                .with_context(|| format!("Failed to start MCP server '{name}'"))?;
+            let tools = with_timeout(timeout, client.list_all_tools())
+                .await
+                .with_context(|| format!("Failed to list tools of MCP server '{name}'"))?;
+            for tool in &tools {
+                let qualified = qualify_name(name, &separator, &tool.name);
+                if qualified.len() > MAX_NAME_LENGTH || !qualified.chars().all(is_name_char) {
+                    warn!(
+                        tool = qualified,
+                        "The tool name is longer than {MAX_NAME_LENGTH} characters or has other \
+                         characters than letters, digits, '_' and '-', the requests with it will \
+                         likely be turned down"
+                    );
+                }
+            }
+            servers.insert(
+                name.clone(),
+                Server {
+                    client,
+                    tools,
+                    timeout,
+                },
+            );
         }
-        let client = ().serve(TokioChildProcess::new(&mut cmd)?).await?;
-        Ok(MCP { client })
+        Ok(MCP { servers, separator })
+    }
+    pub fn server_names(&self) -> impl Iterator<Item = &str> {
+        self.servers.keys().map(String::as_str)
+    }
+    // The instructions the servers sent when they were initialized, for the servers that did
+    pub fn instructions(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.servers.iter().filter_map(|(name, server)| {
+            let instructions = server.client.peer_info().instructions.as_deref()?;
+            Some((name.as_str(), instructions))
+        })
     }
-    pub async fn list_tools(&self) -> anyhow::Result<Vec<ChatCompletionTool>> {
+    pub fn list_tools(&self) -> anyhow::Result<Vec<ChatCompletionTool>> {
         let tools = self
-            .client
-            .list_all_tools()
-            .await?
-            .into_iter()
-            .map(tool_to_function)
+            .servers
+            .iter()
+            .flat_map(|(name, server)| server.tools.iter().map(move |tool| (name, tool)))
+            .map(|(name, tool)| tool_to_function(name, &self.separator, tool))
             .collect::<anyhow::Result<Vec<ChatCompletionTool>>>()?;
         Ok(tools)
     }
     pub async fn call_tool(&self, tool: &FunctionCall) -> anyhow::Result<CallToolResult> {
-        Ok(self.client.call_tool(function_to_tool(tool)?).await?)
+        let (server, name) = split_name(&tool.name, &self.separator).ok_or(anyhow!(
+            "Tool '{}' is not prefixed with a server name and '{}'",
+            tool.name,
+            self.separator
+        ))?;
+        let server = self.servers.get(server).ok_or(anyhow!(
+            "Tool '{}' refers to an unknown MCP server '{}', the known servers are: {}",
+            tool.name,
+            server,
+            self.servers
+                .keys()
+                .cloned()
+                .collect::<Vec<String>>()
+                .join(", ")
+        ))?;
+        let request = ClientRequest::CallToolRequest(CallToolRequest {
+            method: Default::default(),
+            params: function_to_tool(name, tool)?,
+        });
+        let handle = server
+            .client
+            .send_cancellable_request(request, PeerRequestOptions::no_options())
+            .await?;
+        let mut guard = CancelOnDrop {
+            peer: handle.peer,
+            id: Some(handle.id),
+            reason: "cancelled by the user",
+        };
+        let response = match tokio::time::timeout(server.timeout, handle.rx).await {
+            Ok(response) => {
+                guard.id = None;
+                response.map_err(|_| anyhow!("MCP server of tool '{}' is gone", tool.name))??
+            }
+            Err(_) => {
+                guard.reason = "request timeout";
+                bail!(
+                    "Tool '{}' didn't finish in {} seconds",
+                    tool.name,
+                    server.timeout.as_secs()
+                )
+            }
+        };
+        match response {
+            ServerResult::CallToolResult(result) => Ok(result),
+            _ => bail!("Unexpected response to a call of tool '{}'", tool.name),
+        }
+    }
+}
+
+// The server may still be working on a request that rullm no longer waits for, because it timed
+// out or because the turn was cancelled with Ctrl-C. It's told to stop when that happens
+struct CancelOnDrop {
+    peer: Peer<RoleClient>,
+    // None once the answer has arrived
+    id: Option<RequestId>,
+    reason: &'static str,
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        let Some(request_id) = self.id.take() else {
+            return;
+        };
+        let peer = self.peer.clone();
+        let reason = Some(String::from(self.reason));
+        tokio::spawn(async move {
+            let _ = peer
+                .notify_cancelled(CancelledNotificationParam { request_id, reason })
+                .await;
+        });
+    }
+}
+
+async fn with_timeout<T, E: Into<anyhow::Error>>(
+    timeout: Duration,
+    future: impl Future<Output = Result<T, E>>,
+) -> anyhow::Result<T> {
+    match tokio::time::timeout(timeout, future).await {
+        Ok(result) => result.map_err(Into::into),
+        Err(_) => bail!("Timed out after {} seconds", timeout.as_secs()),
+    }
+}
+
+// The separator ends up in every tool name, so it has to be allowed in one
+fn check_separator(separator: &str) -> anyhow::Result<()> {
+    if separator.is_empty() {
+        bail!("The tool name separator can't be empty")
     }
+    if !separator.chars().all(is_name_char) {
+        bail!("The tool name separator '{separator}' can only have letters, digits, '_' and '-'")
+    }
+    Ok(())
+}
+
+fn qualify_name(server: &str, separator: &str, tool: &str) -> String {
+    format!("{server}{separator}{tool}")
+}
+
+// Server names can't contain the separator, so the first occurrence is the boundary
+fn split_name<'a>(name: &'a str, separator: &str) -> Option<(&'a str, &'a str)> {
+    name.split_once(separator)
 }
 
-fn function_to_tool(function: &FunctionCall) -> anyhow::Result<CallToolRequestParam> {
+fn function_to_tool(name: &str, function: &FunctionCall) -> anyhow::Result<CallToolRequestParam> {
     if let Value::Object(obj) = serde_json::from_str(&function.arguments)? {
         Ok(CallToolRequestParam {
-            name: function.name.clone().into(),
+            name: name.to_string().into(),
             arguments: Some(obj),
         })
     } else {
@@ -63,7 +226,11 @@ fn function_to_tool(function: &FunctionCall) -> anyhow::Result<CallToolRequestPa
     }
 }
 
-fn tool_to_function(tool: Tool) -> anyhow::Result<ChatCompletionTool> {
+fn tool_to_function(
+    server: &str,
+    separator: &str,
+    tool: &Tool,
+) -> anyhow::Result<ChatCompletionTool> {
     let obj: &JsonObject = &tool.input_schema;
     let parameters: Option<Value> = if obj.contains_key("properties") {
         Some(Value::Object(obj.clone()))
@@ -72,7 +239,7 @@ fn tool_to_function(tool: Tool) -> anyhow::Result<ChatCompletionTool> {
     };
     let x = ChatCompletionToolArgs::default()
         .function(FunctionObject {
-            name: tool.name.to_string(),
+            name: qualify_name(server, separator, &tool.name),
             description: Some(tool.description.to_string()),
             parameters,
             strict: None,
@@ -80,3 +247,33 @@ fn tool_to_function(tool: Tool) -> anyhow::Result<ChatCompletionTool> {
         .build()?;
     Ok(x)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+
+        #[test]
+        fn test_split_qualified_name(server in "[a-z]{1,8}", tool in "[a-z_]{1,12}", separator in "(__|-)") {
+            prop_assume!(!server.contains(&separator));
+            let qualified = qualify_name(&server, &separator, &tool);
+            assert_eq!(Some((server.as_str(), tool.as_str())), split_name(&qualified, &separator));
+        }
+    }
+
+    #[test]
+    fn test_split_unqualified_name() {
+        assert_eq!(None, split_name("add_to_list", "__"));
+    }
+
+    #[test]
+    fn test_check_separator() {
+        assert!(check_separator("__").is_ok());
+        assert!(check_separator("-").is_ok());
+        assert!(check_separator("").is_err());
+        assert!(check_separator(".").is_err());
+        assert!(check_separator("::").is_err());
+    }
+}
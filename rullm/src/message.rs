@@ -0,0 +1,65 @@
+use async_openai::types::{
+    ChatCompletionRequestAssistantMessageContent, ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessageContent, ChatCompletionRequestToolMessageContent,
+    ChatCompletionRequestUserMessageContent,
+};
+
+// A new turn starts at every message typed by the user. Images from tools are sent in user
+// messages too, but with parts, and they belong to the turn of the tool call
+pub fn is_turn_start(message: &ChatCompletionRequestMessage) -> bool {
+    match message {
+        ChatCompletionRequestMessage::User(user) => {
+            matches!(
+                user.content,
+                ChatCompletionRequestUserMessageContent::Text(_)
+            )
+        }
+        _ => false,
+    }
+}
+
+pub fn message_role(message: &ChatCompletionRequestMessage) -> &'static str {
+    match message {
+        ChatCompletionRequestMessage::Developer(_) => "developer",
+        ChatCompletionRequestMessage::System(_) => "system",
+        ChatCompletionRequestMessage::User(_) => "user",
+        ChatCompletionRequestMessage::Assistant(_) => "assistant",
+        ChatCompletionRequestMessage::Tool(_) => "tool",
+        ChatCompletionRequestMessage::Function(_) => "function",
+    }
+}
+
+// The text parts of a message. Tool calls are shown by their names
+pub fn message_text(message: &ChatCompletionRequestMessage) -> String {
+    match message {
+        ChatCompletionRequestMessage::System(x) => match &x.content {
+            ChatCompletionRequestSystemMessageContent::Text(text) => text.clone(),
+            ChatCompletionRequestSystemMessageContent::Array(_) => String::from("[parts]"),
+        },
+        ChatCompletionRequestMessage::User(x) => match &x.content {
+            ChatCompletionRequestUserMessageContent::Text(text) => text.clone(),
+            ChatCompletionRequestUserMessageContent::Array(_) => String::from("[parts]"),
+        },
+        ChatCompletionRequestMessage::Assistant(x) => {
+            let mut text = match &x.content {
+                Some(ChatCompletionRequestAssistantMessageContent::Text(text)) => text.clone(),
+                Some(ChatCompletionRequestAssistantMessageContent::Array(_)) => {
+                    String::from("[parts]")
+                }
+                None => String::new(),
+            };
+            for call in x.tool_calls.iter().flatten() {
+                text.push_str(&format!(
+                    "\n  -> {}({})",
+                    call.function.name, call.function.arguments
+                ));
+            }
+            text
+        }
+        ChatCompletionRequestMessage::Tool(x) => match &x.content {
+            ChatCompletionRequestToolMessageContent::Text(text) => text.clone(),
+            ChatCompletionRequestToolMessageContent::Array(_) => String::from("[parts]"),
+        },
+        _ => String::new(),
+    }
+}
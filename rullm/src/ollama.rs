@@ -0,0 +1,676 @@
+use std::{cell::Cell, collections::HashMap, time::Duration};
+
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessageContent,
+    ChatCompletionRequestAssistantMessageContentPart, ChatCompletionRequestDeveloperMessageContent,
+    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageContent,
+    ChatCompletionRequestSystemMessageContentPart, ChatCompletionRequestToolMessageContent,
+    ChatCompletionRequestToolMessageContentPart, ChatCompletionRequestUserMessageContent,
+    ChatCompletionRequestUserMessageContentPart, ChatCompletionTool, ChatCompletionToolType,
+    CompletionUsage, FunctionCall,
+};
+use async_trait::async_trait;
+use futures::StreamExt as _;
+use reqwest::{RequestBuilder, StatusCode};
+use rmcp::serde_json::{self, Value, json};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{
+    conf::{LLMConfig, RetryConfig, Sampling},
+    provider::{Provider, Reply, interrupted, timed_out},
+    retry::{Failure, HttpError, retry},
+};
+
+// The native API of Ollama. Its OpenAI compatible endpoint works with the `openai` provider
+// too, but this one can also work with models that don't support tools
+pub struct OllamaClient {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: String,
+    model: String,
+    tools: Vec<ChatCompletionTool>,
+    stream: bool,
+    retry: RetryConfig,
+    timeout: Duration,
+    sampling: Sampling,
+    tool_fallback: bool,
+    // Set when the server turns down the tools of the current model
+    fallback: Cell<bool>,
+}
+
+impl OllamaClient {
+    pub fn build(conf: &LLMConfig, tools: Vec<ChatCompletionTool>) -> OllamaClient {
+        let base_url = conf
+            .base_url
+            .clone()
+            .unwrap_or(String::from("http://localhost:11434"));
+        let model = conf.model.clone().unwrap_or(String::from("llama3.1"));
+        let tool_fallback = conf.tool_fallback.unwrap_or(false);
+        OllamaClient {
+            http: reqwest::Client::new(),
+            base_url,
+            api_key: conf.api_key.clone(),
+            model,
+            tools,
+            stream: conf.stream.unwrap_or(true),
+            retry: conf.retry.clone(),
+            timeout: Duration::from_secs(conf.timeout_secs.unwrap_or(120)),
+            sampling: conf.sampling.clone(),
+            tool_fallback,
+            fallback: Cell::new(tool_fallback),
+        }
+    }
+
+    fn request(
+        &self,
+        messages: &[ChatCompletionRequestMessage],
+        tools: bool,
+        sampling: &Sampling,
+    ) -> Value {
+        let fallback = tools && self.fallback.get() && !self.tools.is_empty();
+        let mut messages = convert_messages(messages, fallback);
+        if fallback {
+            add_to_system_prompt(&mut messages, &tools_prompt(&self.tools));
+        }
+        let mut request = json!({
+            "model": self.model,
+            "messages": messages,
+            "stream": false,
+        });
+        if tools && !fallback && !self.tools.is_empty() {
+            request["tools"] = json!(self.tools);
+        }
+        // Ollama takes the sampling settings in `options`, under its own names. It has no say in
+        // how tools are called, nor a reasoning effort
+        let options = json!({
+            "temperature": sampling.temperature,
+            "top_p": sampling.top_p,
+            "num_predict": sampling.max_tokens,
+            "seed": sampling.seed,
+            "stop": sampling.stop,
+        });
+        let Value::Object(mut options) = options else {
+            unreachable!()
+        };
+        options.retain(|_, value| !value.is_null());
+        if !options.is_empty() {
+            request["options"] = Value::Object(options);
+        }
+        request
+    }
+
+    async fn create(&self, request: &Value) -> anyhow::Result<Reply> {
+        retry(&self.retry, async || self.create_once(request).await).await
+    }
+
+    async fn create_once(&self, request: &Value) -> Result<Reply, Failure> {
+        let response = self
+            .post(request)
+            .timeout(self.timeout)
+            .send()
+            .await
+            .map_err(Failure::from_reqwest)?;
+        if !response.status().is_success() {
+            return Err(Failure::from_response(response).await);
+        }
+        let chunk: Chunk = response.json().await?;
+        if let Some(error) = chunk.error {
+            return Err(Failure::Permanent(anyhow::anyhow!(error)));
+        }
+        let mut reply = Reply::default();
+        chunk.add_to(&mut reply);
+        Ok(reply)
+    }
+
+    // The answer is streamed as lines of JSON rather than as server-sent events
+    async fn chat_stream(
+        &self,
+        request: &Value,
+        on_token: &mut dyn FnMut(&str),
+    ) -> Result<Reply, Failure> {
+        let response = tokio::time::timeout(self.timeout, self.post(request).send())
+            .await
+            .map_err(|_| timed_out(self.timeout, false))?
+            .map_err(Failure::from_reqwest)?;
+        if !response.status().is_success() {
+            return Err(Failure::from_response(response).await);
+        }
+        let mut bytes = response.bytes_stream();
+        let mut buffer: Vec<u8> = vec![];
+        let mut reply = Reply::default();
+        let mut received = false;
+        loop {
+            while let Some(end) = buffer.iter().position(|x| *x == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                if line.trim_ascii().is_empty() {
+                    continue;
+                }
+                let chunk: Chunk = serde_json::from_slice(&line)?;
+                if let Some(error) = chunk.error {
+                    return Err(Failure::Permanent(anyhow::anyhow!(error)));
+                }
+                if let Some(message) = &chunk.message
+                    && !message.content.is_empty()
+                {
+                    received = true;
+                    on_token(&message.content);
+                }
+                let done = chunk.done;
+                chunk.add_to(&mut reply);
+                if done {
+                    return Ok(reply);
+                }
+            }
+            match tokio::time::timeout(self.timeout, bytes.next()).await {
+                Err(_) => return Err(timed_out(self.timeout, received)),
+                // The last line has `done` set, without it the answer was cut off
+                Ok(None) => {
+                    let error =
+                        anyhow::anyhow!("The connection was closed in the middle of the answer");
+                    return Err(interrupted(error, received));
+                }
+                Ok(Some(Err(error))) if !received => return Err(Failure::from_reqwest(error)),
+                Ok(Some(Err(error))) => return Err(error.into()),
+                Ok(Some(Ok(chunk))) => buffer.extend_from_slice(&chunk),
+            }
+        }
+    }
+
+    // Without tool support the calls are in the text, so the answer can't be shown before it's
+    // complete
+    async fn chat_with_fallback(
+        &self,
+        messages: &[ChatCompletionRequestMessage],
+        sampling: &Sampling,
+        on_token: &mut dyn FnMut(&str),
+    ) -> anyhow::Result<Reply> {
+        let mut reply = self.create(&self.request(messages, true, sampling)).await?;
+        let (content, calls) = parse_tool_calls(&reply.content, &self.tools);
+        reply.content = content;
+        reply.tool_calls.extend(
+            calls
+                .into_iter()
+                .map(|(name, arguments)| tool_call(name, arguments)),
+        );
+        on_token(&reply.content);
+        Ok(reply)
+    }
+
+    fn post(&self, request: &Value) -> RequestBuilder {
+        let request = self
+            .http
+            .post(format!("{}/api/chat", self.base_url))
+            .json(request);
+        // Ollama itself doesn't need a key, but a proxy in front of it might
+        match self.api_key.is_empty() {
+            true => request,
+            false => request.bearer_auth(&self.api_key),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Provider for OllamaClient {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn set_model(&mut self, model: &str) {
+        self.model = String::from(model);
+        self.fallback.set(self.tool_fallback);
+    }
+
+    fn tools(&self) -> &[ChatCompletionTool] {
+        &self.tools
+    }
+
+    fn sampling(&self) -> &Sampling {
+        &self.sampling
+    }
+
+    async fn chat(
+        &self,
+        messages: &[ChatCompletionRequestMessage],
+        overrides: &Sampling,
+        on_token: &mut dyn for<'t> FnMut(&'t str),
+    ) -> anyhow::Result<Reply> {
+        let sampling = overrides.or(&self.sampling);
+        if self.fallback.get() {
+            return self.chat_with_fallback(messages, &sampling, on_token).await;
+        }
+        let mut request = self.request(messages, true, &sampling);
+        request["stream"] = Value::Bool(self.stream);
+        let result = retry(&self.retry, async || match self.stream {
+            true => self.chat_stream(&request, on_token).await,
+            false => self
+                .create_once(&request)
+                .await
+                .inspect(|x| on_token(&x.content)),
+        })
+        .await;
+        match result {
+            Err(error) if !self.tools.is_empty() && tools_unsupported(&error) => {
+                warn!(
+                    model = self.model,
+                    "The model doesn't support tools, describing them in the prompt"
+                );
+                self.fallback.set(true);
+                self.chat_with_fallback(messages, &sampling, on_token).await
+            }
+            result => result,
+        }
+    }
+
+    async fn complete(&self, messages: &[ChatCompletionRequestMessage]) -> anyhow::Result<Reply> {
+        self.create(&self.request(messages, false, &self.sampling))
+            .await
+    }
+}
+
+// A line of a streamed answer, or the whole answer
+#[derive(Deserialize)]
+struct Chunk {
+    message: Option<Message>,
+    #[serde(default)]
+    done: bool,
+    prompt_eval_count: Option<u32>,
+    eval_count: Option<u32>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Message {
+    #[serde(default)]
+    content: String,
+    #[serde(default)]
+    tool_calls: Vec<ToolCall>,
+}
+
+#[derive(Deserialize)]
+struct ToolCall {
+    function: Function,
+}
+
+#[derive(Deserialize)]
+struct Function {
+    name: String,
+    arguments: Value,
+}
+
+impl Chunk {
+    fn add_to(self, reply: &mut Reply) {
+        if let Some(message) = self.message {
+            reply.content.push_str(&message.content);
+            reply.tool_calls.extend(
+                message
+                    .tool_calls
+                    .into_iter()
+                    .map(|x| tool_call(x.function.name, x.function.arguments)),
+            );
+        }
+        // The counts come with the last chunk
+        if self.done {
+            let prompt_tokens = self.prompt_eval_count.unwrap_or(0);
+            let completion_tokens = self.eval_count.unwrap_or(0);
+            reply.usage = Some(CompletionUsage {
+                prompt_tokens,
+                completion_tokens,
+                total_tokens: prompt_tokens + completion_tokens,
+                prompt_tokens_details: None,
+                completion_tokens_details: None,
+            });
+        }
+    }
+}
+
+// Ollama doesn't give the calls ids, but the history needs them to match the results to calls
+fn tool_call(name: String, arguments: Value) -> ChatCompletionMessageToolCall {
+    ChatCompletionMessageToolCall {
+        id: format!("call_{:08x}", rand::random::<u32>()),
+        r#type: ChatCompletionToolType::Function,
+        function: FunctionCall {
+            name,
+            arguments: arguments.to_string(),
+        },
+    }
+}
+
+// Ollama turns down a request that has tools with a 400 when the model doesn't support them
+fn tools_unsupported(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<HttpError>().is_some_and(|x| {
+        x.status == StatusCode::BAD_REQUEST && x.message.contains("does not support tools")
+    })
+}
+
+fn tools_prompt(tools: &[ChatCompletionTool]) -> String {
+    let tools: Vec<Value> = tools
+        .iter()
+        .map(|x| {
+            json!({
+                "name": x.function.name,
+                "description": x.function.description,
+                "parameters": x.function.parameters,
+            })
+        })
+        .collect();
+    format!(
+        "You can use the following tools:\n{}\n\n\
+        To use a tool, answer with a JSON object of the form \
+        {{\"name\": \"<tool name>\", \"arguments\": {{<arguments>}}}} and nothing else, one object \
+        for every call. The results of the calls are sent to you in the next message.",
+        Value::Array(tools)
+    )
+}
+
+fn add_to_system_prompt(messages: &mut Vec<Value>, text: &str) {
+    match messages.first_mut() {
+        Some(first) if first["role"] == "system" => {
+            let content = first["content"].as_str().unwrap_or_default();
+            first["content"] = Value::String(format!("{content}\n\n{text}"));
+        }
+        _ => messages.insert(0, json!({"role": "system", "content": text})),
+    }
+}
+
+// With the fallback the model never saw the calls and the results in the format of the API, so
+// they are given back to it as text
+fn convert_messages(messages: &[ChatCompletionRequestMessage], fallback: bool) -> Vec<Value> {
+    let mut names: HashMap<&str, &str> = HashMap::new();
+    let mut converted = vec![];
+    for message in messages {
+        let message = match message {
+            ChatCompletionRequestMessage::System(x) => {
+                let content: String = match &x.content {
+                    ChatCompletionRequestSystemMessageContent::Text(text) => text.clone(),
+                    ChatCompletionRequestSystemMessageContent::Array(parts) => parts
+                        .iter()
+                        .map(|ChatCompletionRequestSystemMessageContentPart::Text(x)| {
+                            x.text.as_str()
+                        })
+                        .collect(),
+                };
+                json!({"role": "system", "content": content})
+            }
+            ChatCompletionRequestMessage::Developer(x) => {
+                let content: String = match &x.content {
+                    ChatCompletionRequestDeveloperMessageContent::Text(text) => text.clone(),
+                    ChatCompletionRequestDeveloperMessageContent::Array(parts) => {
+                        parts.iter().map(|x| x.text.as_str()).collect()
+                    }
+                };
+                json!({"role": "system", "content": content})
+            }
+            ChatCompletionRequestMessage::User(x) => {
+                let mut texts: Vec<&str> = vec![];
+                // Just the base64 data, without the `data:` prefix
+                let mut images: Vec<&str> = vec![];
+                match &x.content {
+                    ChatCompletionRequestUserMessageContent::Text(text) => texts.push(text),
+                    ChatCompletionRequestUserMessageContent::Array(parts) => {
+                        for part in parts {
+                            match part {
+                                ChatCompletionRequestUserMessageContentPart::Text(x) => {
+                                    texts.push(&x.text)
+                                }
+                                ChatCompletionRequestUserMessageContentPart::ImageUrl(x) => {
+                                    if let Some((_, data)) = x.image_url.url.split_once(";base64,")
+                                    {
+                                        images.push(data);
+                                    }
+                                }
+                                ChatCompletionRequestUserMessageContentPart::InputAudio(_) => {}
+                            }
+                        }
+                    }
+                }
+                let mut message = json!({"role": "user", "content": texts.join("\n")});
+                if !images.is_empty() {
+                    message["images"] = json!(images);
+                }
+                message
+            }
+            ChatCompletionRequestMessage::Assistant(x) => {
+                let mut content: String = match &x.content {
+                    Some(ChatCompletionRequestAssistantMessageContent::Text(text)) => text.clone(),
+                    Some(ChatCompletionRequestAssistantMessageContent::Array(parts)) => parts
+                        .iter()
+                        .map(|part| match part {
+                            ChatCompletionRequestAssistantMessageContentPart::Text(x) => {
+                                x.text.as_str()
+                            }
+                            ChatCompletionRequestAssistantMessageContentPart::Refusal(x) => {
+                                x.refusal.as_str()
+                            }
+                        })
+                        .collect(),
+                    None => String::new(),
+                };
+                let mut calls = vec![];
+                for call in x.tool_calls.iter().flatten() {
+                    names.insert(&call.id, &call.function.name);
+                    let arguments = serde_json::from_str::<Value>(&call.function.arguments)
+                        .ok()
+                        .filter(Value::is_object)
+                        .unwrap_or(json!({}));
+                    calls.push(json!({"name": call.function.name, "arguments": arguments}));
+                }
+                if fallback {
+                    for call in calls {
+                        if !content.is_empty() {
+                            content.push('\n');
+                        }
+                        content.push_str(&call.to_string());
+                    }
+                    json!({"role": "assistant", "content": content})
+                } else {
+                    let calls: Vec<Value> =
+                        calls.into_iter().map(|x| json!({"function": x})).collect();
+                    json!({"role": "assistant", "content": content, "tool_calls": calls})
+                }
+            }
+            ChatCompletionRequestMessage::Tool(x) => {
+                let content: String = match &x.content {
+                    ChatCompletionRequestToolMessageContent::Text(text) => text.clone(),
+                    ChatCompletionRequestToolMessageContent::Array(parts) => parts
+                        .iter()
+                        .map(|ChatCompletionRequestToolMessageContentPart::Text(x)| x.text.as_str())
+                        .collect(),
+                };
+                let name = names
+                    .get(x.tool_call_id.as_str())
+                    .copied()
+                    .unwrap_or_default();
+                match fallback {
+                    true => json!({
+                        "role": "user",
+                        "content": format!("The result of {name}: {content}"),
+                    }),
+                    false => json!({"role": "tool", "content": content, "tool_name": name}),
+                }
+            }
+            ChatCompletionRequestMessage::Function(_) => continue,
+        };
+        converted.push(message);
+    }
+    converted
+}
+
+// Finds the tool calls that the model wrote into its answer, objects like
+// `{"name": "notes__add", "arguments": {...}}` or arrays of them. Only the names of known tools
+// count, so that JSON that belongs to the answer itself is left alone. Gives back the text
+// without the calls
+fn parse_tool_calls(text: &str, tools: &[ChatCompletionTool]) -> (String, Vec<(String, Value)>) {
+    let as_call = |value: &Value| -> Option<(String, Value)> {
+        let name = value.get("name")?.as_str()?;
+        if !tools.iter().any(|x| x.function.name == name) {
+            return None;
+        }
+        let arguments = value
+            .get("arguments")
+            .or(value.get("parameters"))
+            .cloned()
+            .unwrap_or(json!({}));
+        Some((String::from(name), arguments))
+    };
+    let mut rest = String::new();
+    let mut calls = vec![];
+    // The end of the last call
+    let mut last = 0;
+    let mut start = 0;
+    while let Some(offset) = text[start..].find(['{', '[']) {
+        let begin = start + offset;
+        let mut values = serde_json::Deserializer::from_str(&text[begin..]).into_iter::<Value>();
+        let Some(Ok(value)) = values.next() else {
+            start = begin + 1;
+            continue;
+        };
+        let end = begin + values.byte_offset();
+        start = end;
+        let found: Option<Vec<(String, Value)>> = match &value {
+            Value::Array(values) if !values.is_empty() => values.iter().map(as_call).collect(),
+            value => as_call(value).map(|x| vec![x]),
+        };
+        let Some(found) = found else {
+            continue;
+        };
+        calls.extend(found);
+        // A code block around the call goes with it
+        let before = text[last..begin].trim_end();
+        let after = &text[end..];
+        let fenced = before
+            .strip_suffix("```json")
+            .or(before.strip_suffix("```"))
+            .zip(after.trim_start().strip_prefix("```"));
+        match fenced {
+            Some((before, after)) => {
+                rest.push_str(before);
+                last = text.len() - after.len();
+            }
+            None => {
+                rest.push_str(&text[last..begin]);
+                last = end;
+            }
+        }
+        start = last;
+    }
+    rest.push_str(&text[last..]);
+    (rest.trim().to_string(), calls)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::{
+        ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestToolMessageArgs,
+        ChatCompletionRequestUserMessageArgs, FunctionObject,
+    };
+
+    fn tools() -> Vec<ChatCompletionTool> {
+        vec![ChatCompletionTool {
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionObject {
+                name: String::from("notes__add"),
+                description: None,
+                parameters: None,
+                strict: None,
+            },
+        }]
+    }
+
+    #[test]
+    fn test_parse_tool_calls() {
+        let tools = tools();
+        let parse = |text: &str| parse_tool_calls(text, &tools);
+        assert_eq!(
+            (
+                String::from("Adding it."),
+                vec![(String::from("notes__add"), json!({"text": "milk"}))]
+            ),
+            parse(
+                "Adding it.\n```json\n{\"name\": \"notes__add\", \"arguments\": {\"text\": \"milk\"}}\n```\n"
+            )
+        );
+        assert_eq!(
+            (
+                String::new(),
+                vec![
+                    (String::from("notes__add"), json!({"text": "a"})),
+                    (String::from("notes__add"), json!({})),
+                ]
+            ),
+            parse(
+                "[{\"name\": \"notes__add\", \"parameters\": {\"text\": \"a\"}}, {\"name\": \"notes__add\"}]"
+            )
+        );
+        // Other JSON and unknown tools stay in the answer
+        let text = "Use {\"name\": \"rm\", \"arguments\": {}} or {\"a\": [1, 2]} {broken";
+        assert_eq!((String::from(text), vec![]), parse(text));
+    }
+
+    #[test]
+    fn test_convert_messages_for_fallback() {
+        let call = ChatCompletionMessageToolCall {
+            id: String::from("call_1"),
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionCall {
+                name: String::from("notes__add"),
+                arguments: String::from("{\"text\":\"milk\"}"),
+            },
+        };
+        let messages: Vec<ChatCompletionRequestMessage> = vec![
+            ChatCompletionRequestUserMessageArgs::default()
+                .content("Note milk")
+                .build()
+                .unwrap()
+                .into(),
+            ChatCompletionRequestAssistantMessageArgs::default()
+                .tool_calls(vec![call])
+                .build()
+                .unwrap()
+                .into(),
+            ChatCompletionRequestToolMessageArgs::default()
+                .tool_call_id("call_1")
+                .content("added")
+                .build()
+                .unwrap()
+                .into(),
+        ];
+        assert_eq!(
+            json!([
+                {"role": "user", "content": "Note milk"},
+                {"role": "assistant", "content": "{\"arguments\":{\"text\":\"milk\"},\"name\":\"notes__add\"}"},
+                {"role": "user", "content": "The result of notes__add: added"},
+            ]),
+            Value::Array(convert_messages(&messages, true))
+        );
+        assert_eq!(
+            json!({"role": "tool", "content": "added", "tool_name": "notes__add"}),
+            convert_messages(&messages, false)[2]
+        );
+    }
+
+    #[test]
+    fn test_tools_unsupported() {
+        let error = |status, message: &str| {
+            anyhow::Error::new(HttpError {
+                status,
+                message: String::from(message),
+            })
+        };
+        let message = "registry.ollama.ai/library/gemma:2b does not support tools";
+        assert!(tools_unsupported(&error(StatusCode::BAD_REQUEST, message)));
+        assert!(!tools_unsupported(&error(
+            StatusCode::BAD_REQUEST,
+            "invalid options"
+        )));
+        assert!(!tools_unsupported(&error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            message
+        )));
+        // Only the error from the response counts, not whatever text ends up in the message
+        assert!(!tools_unsupported(&anyhow::anyhow!(
+            "400 Bad Request: {message}"
+        )));
+    }
+}
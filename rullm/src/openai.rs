@@ -1,56 +1,346 @@
+use std::{collections::BTreeMap, time::Duration};
+
 use async_openai::{
-    Client,
-    config::OpenAIConfig,
+    config::{Config as _, OpenAIConfig},
     types::{
-        ChatCompletionRequestMessage, ChatCompletionTool, CreateChatCompletionRequestArgs,
-        CreateChatCompletionResponse,
+        ChatCompletionMessageToolCall, ChatCompletionMessageToolCallChunk,
+        ChatCompletionNamedToolChoice, ChatCompletionRequestMessage, ChatCompletionStreamOptions,
+        ChatCompletionTool, ChatCompletionToolChoiceOption, ChatCompletionToolType,
+        CompletionUsage, CreateChatCompletionRequest, CreateChatCompletionRequestArgs,
+        CreateChatCompletionResponse, CreateChatCompletionStreamResponse, FunctionCall,
+        FunctionName, ReasoningEffort, Stop,
     },
 };
+use async_trait::async_trait;
+use reqwest::RequestBuilder;
+use reqwest_eventsource::{EventSource, retry::Never};
+use rmcp::serde_json;
 
-use crate::{conf::Conf, mcp::MCP};
+use crate::{
+    conf::{self, LLMConfig, RetryConfig, Sampling},
+    provider::{Provider, Reply, next_event},
+    retry::{Failure, retry},
+};
 
+// The requests are made with reqwest rather than with the async-openai client, which doesn't
+// tell the status code or the headers of a failed response. Those are needed for the retries
 pub struct OpenAIClient {
-    client: Client<OpenAIConfig>,
+    http: reqwest::Client,
+    config: OpenAIConfig,
     model: String,
     tools: Vec<ChatCompletionTool>,
+    stream: bool,
+    retry: RetryConfig,
+    timeout: Duration,
+    sampling: Sampling,
 }
 
 impl OpenAIClient {
-    pub async fn build(conf: &Conf, mcp: &MCP) -> anyhow::Result<OpenAIClient> {
+    pub fn build(conf: &LLMConfig, tools: Vec<ChatCompletionTool>) -> OpenAIClient {
         let openai_base = conf
-            .llm
             .base_url
-            .as_ref()
-            .map(|x| x.clone())
+            .clone()
             .unwrap_or(String::from("https://api.openai.com/v1"));
-        let openai_config = OpenAIConfig::default()
-            .with_api_key(&conf.llm.api_key)
+        let config = OpenAIConfig::default()
+            .with_api_key(&conf.api_key)
             .with_api_base(openai_base);
-        let client = Client::with_config(openai_config);
         let model = conf
-            .llm
             .model
             .as_ref()
             .cloned()
             .unwrap_or(String::from("gpt-4o"));
-        let tools = mcp.list_tools().await?;
-        Ok(OpenAIClient {
-            client,
+        let stream = conf.stream.unwrap_or(true);
+        OpenAIClient {
+            http: reqwest::Client::new(),
+            config,
             model,
             tools,
-        })
+            stream,
+            retry: conf.retry.clone(),
+            timeout: Duration::from_secs(conf.timeout_secs.unwrap_or(120)),
+            sampling: conf.sampling.clone(),
+        }
     }
 
-    pub async fn chat(
+    fn request(
         &self,
-        messages: &Vec<ChatCompletionRequestMessage>,
-    ) -> anyhow::Result<CreateChatCompletionResponse> {
-        let request = CreateChatCompletionRequestArgs::default()
+        messages: &[ChatCompletionRequestMessage],
+        tools: bool,
+        sampling: &Sampling,
+    ) -> anyhow::Result<CreateChatCompletionRequest> {
+        let mut request = CreateChatCompletionRequestArgs::default();
+        request
             .model(self.model.clone())
-            .messages(messages.clone())
-            .tools(self.tools.clone())
-            .build()?;
-        let response = self.client.chat().create(request).await?;
-        Ok(response)
+            .messages(messages.to_vec());
+        // The server turns down an empty list of tools, as it does these below when there are no
+        // tools
+        let tools = tools && !self.tools.is_empty();
+        if tools {
+            request.tools(self.tools.clone());
+        }
+        let mut request = CreateChatCompletionRequest {
+            temperature: sampling.temperature.map(|x| x as f32),
+            top_p: sampling.top_p.map(|x| x as f32),
+            max_completion_tokens: sampling.max_tokens,
+            seed: sampling.seed,
+            stop: sampling.stop.clone().map(Stop::StringArray),
+            reasoning_effort: sampling.reasoning_effort.map(|x| match x {
+                conf::ReasoningEffort::Low => ReasoningEffort::Low,
+                conf::ReasoningEffort::Medium => ReasoningEffort::Medium,
+                conf::ReasoningEffort::High => ReasoningEffort::High,
+            }),
+            ..request.build()?
+        };
+        if tools {
+            request.parallel_tool_calls = sampling.parallel_tool_calls;
+            request.tool_choice = sampling.tool_choice.as_deref().map(tool_choice);
+        }
+        Ok(request)
+    }
+
+    async fn create(&self, request: &CreateChatCompletionRequest) -> anyhow::Result<Reply> {
+        retry(&self.retry, async || self.create_once(request).await).await
+    }
+
+    async fn create_once(&self, request: &CreateChatCompletionRequest) -> Result<Reply, Failure> {
+        let response = self
+            .post(request)
+            .timeout(self.timeout)
+            .send()
+            .await
+            .map_err(Failure::from_reqwest)?;
+        if !response.status().is_success() {
+            return Err(Failure::from_response(response).await);
+        }
+        let response: CreateChatCompletionResponse = response.json().await?;
+        Ok(Reply::from(response))
+    }
+
+    async fn chat_stream(
+        &self,
+        request: &CreateChatCompletionRequest,
+        on_token: &mut dyn FnMut(&str),
+    ) -> Result<Reply, Failure> {
+        let mut events = EventSource::new(self.post(request))?;
+        events.set_retry_policy(Box::new(Never));
+        let mut reply = StreamedReply::default();
+        let mut received = false;
+        while let Some(data) = next_event(&mut events, self.timeout, received).await? {
+            if data == "[DONE]" {
+                break;
+            }
+            received = true;
+            let response: CreateChatCompletionStreamResponse = serde_json::from_str(&data)?;
+            if response.usage.is_some() {
+                reply.usage = response.usage;
+            }
+            for choice in response.choices {
+                if let Some(content) = choice.delta.content {
+                    on_token(&content);
+                    reply.content.push_str(&content);
+                }
+                for chunk in choice.delta.tool_calls.into_iter().flatten() {
+                    reply.push_tool_call(chunk);
+                }
+            }
+        }
+        events.close();
+        Ok(reply.into())
+    }
+
+    fn post(&self, request: &CreateChatCompletionRequest) -> RequestBuilder {
+        self.http
+            .post(self.config.url("/chat/completions"))
+            .query(&self.config.query())
+            .headers(self.config.headers())
+            .json(request)
+    }
+}
+
+#[async_trait(?Send)]
+impl Provider for OpenAIClient {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn set_model(&mut self, model: &str) {
+        self.model = String::from(model);
+    }
+
+    fn tools(&self) -> &[ChatCompletionTool] {
+        &self.tools
+    }
+
+    fn sampling(&self) -> &Sampling {
+        &self.sampling
+    }
+
+    async fn chat(
+        &self,
+        messages: &[ChatCompletionRequestMessage],
+        overrides: &Sampling,
+        on_token: &mut dyn for<'t> FnMut(&'t str),
+    ) -> anyhow::Result<Reply> {
+        let request = self.request(messages, true, &overrides.or(&self.sampling))?;
+        if !self.stream {
+            let reply = self.create(&request).await?;
+            on_token(&reply.content);
+            return Ok(reply);
+        }
+        let request = CreateChatCompletionRequest {
+            stream: Some(true),
+            // The usage comes in an extra chunk at the end
+            stream_options: Some(ChatCompletionStreamOptions {
+                include_usage: true,
+            }),
+            ..request
+        };
+        retry(&self.retry, async || {
+            self.chat_stream(&request, on_token).await
+        })
+        .await
+    }
+
+    async fn complete(&self, messages: &[ChatCompletionRequestMessage]) -> anyhow::Result<Reply> {
+        self.create(&self.request(messages, false, &self.sampling)?)
+            .await
+    }
+}
+
+fn tool_choice(choice: &str) -> ChatCompletionToolChoiceOption {
+    match choice {
+        "auto" => ChatCompletionToolChoiceOption::Auto,
+        "none" => ChatCompletionToolChoiceOption::None,
+        "required" => ChatCompletionToolChoiceOption::Required,
+        name => ChatCompletionToolChoiceOption::Named(ChatCompletionNamedToolChoice {
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionName {
+                name: String::from(name),
+            },
+        }),
+    }
+}
+
+impl From<CreateChatCompletionResponse> for Reply {
+    fn from(response: CreateChatCompletionResponse) -> Self {
+        let mut text_responses: Vec<String> = vec![];
+        let mut tool_calls: Vec<ChatCompletionMessageToolCall> = vec![];
+        for message in response.choices.into_iter().map(|x| x.message) {
+            if let Some(content) = message.content {
+                text_responses.push(content);
+            }
+            if let Some(tools) = message.tool_calls {
+                tool_calls.extend(tools);
+            }
+        }
+        Reply {
+            content: text_responses.join("\n"),
+            tool_calls,
+            usage: response.usage,
+        }
+    }
+}
+
+// Tool calls arrive in pieces, the first chunk of a call has the id and the name and the rest
+// of them carry fragments of the arguments. The index tells which call a chunk belongs to
+#[derive(Default)]
+pub struct StreamedReply {
+    pub content: String,
+    tool_calls: BTreeMap<u32, ChatCompletionMessageToolCall>,
+    pub usage: Option<CompletionUsage>,
+}
+
+impl StreamedReply {
+    pub fn push_tool_call(&mut self, chunk: ChatCompletionMessageToolCallChunk) {
+        let call =
+            self.tool_calls
+                .entry(chunk.index)
+                .or_insert_with(|| ChatCompletionMessageToolCall {
+                    id: String::new(),
+                    r#type: ChatCompletionToolType::Function,
+                    function: FunctionCall {
+                        name: String::new(),
+                        arguments: String::new(),
+                    },
+                });
+        if let Some(id) = chunk.id.filter(|x| !x.is_empty()) {
+            call.id = id;
+        }
+        if let Some(function) = chunk.function {
+            // Some OpenAI compatible servers repeat the name in every chunk
+            if let Some(name) = function.name.filter(|x| !x.is_empty()) {
+                call.function.name = name;
+            }
+            if let Some(arguments) = function.arguments {
+                call.function.arguments.push_str(&arguments);
+            }
+        }
+    }
+}
+
+impl From<StreamedReply> for Reply {
+    fn from(reply: StreamedReply) -> Self {
+        Reply {
+            content: reply.content,
+            tool_calls: reply.tool_calls.into_values().collect(),
+            usage: reply.usage,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::FunctionCallStream;
+
+    fn chunk(
+        index: u32,
+        id: Option<&str>,
+        name: Option<&str>,
+        arguments: &str,
+    ) -> ChatCompletionMessageToolCallChunk {
+        ChatCompletionMessageToolCallChunk {
+            index,
+            id: id.map(String::from),
+            r#type: id.map(|_| ChatCompletionToolType::Function),
+            function: Some(FunctionCallStream {
+                name: name.map(String::from),
+                arguments: Some(String::from(arguments)),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_assemble_interleaved_tool_calls() {
+        let mut reply = StreamedReply::default();
+        reply.push_tool_call(chunk(0, Some("call_a"), Some("mealie__add_to_list"), ""));
+        reply.push_tool_call(chunk(
+            1,
+            Some("call_b"),
+            Some("mealie__add_to_list"),
+            "{\"na",
+        ));
+        reply.push_tool_call(chunk(0, None, None, "{\"name\": "));
+        reply.push_tool_call(chunk(1, None, None, "me\": \"eggs\"}"));
+        reply.push_tool_call(chunk(0, None, None, "\"milk\"}"));
+        let reply = Reply::from(reply);
+        let calls: Vec<(&str, &str, &str)> = reply
+            .tool_calls
+            .iter()
+            .map(|x| {
+                (
+                    x.id.as_str(),
+                    x.function.name.as_str(),
+                    x.function.arguments.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                ("call_a", "mealie__add_to_list", "{\"name\": \"milk\"}"),
+                ("call_b", "mealie__add_to_list", "{\"name\": \"eggs\"}"),
+            ],
+            calls
+        );
     }
 }
@@ -0,0 +1,114 @@
+use anyhow::anyhow;
+use chrono::Local;
+
+use crate::env::Env;
+
+const DEFAULT_TEMPLATE: &str = "You are a helpful assistant. You know that today is {date}";
+
+// The system prompt of a new session, from the persona given on the command line or the
+// `[prompt]` section
+//
+// The template can refer to these variables:
+// - `{date}` and `{time}`, in the local time
+// - `{timezone}`, like `Europe/Helsinki`
+// - `{hostname}`
+// - `{servers}`, the names of the connected MCP servers
+// - `{instructions}`, what the MCP servers told about themselves. They are appended to the
+//   prompt if the template doesn't use this
+pub fn system_prompt(env: &Env) -> anyhow::Result<String> {
+    let prompt = &env.conf.prompt;
+    let template = match &env.persona {
+        Some(persona) => prompt
+            .personas
+            .get(persona)
+            .ok_or(anyhow!("Unknown persona '{persona}'"))?
+            .template()?
+            .ok_or(anyhow!("Persona '{persona}' has no system prompt"))?,
+        None => prompt
+            .default
+            .template()?
+            .unwrap_or(String::from(DEFAULT_TEMPLATE)),
+    };
+    let now = Local::now();
+    let timezone = iana_time_zone::get_timezone().unwrap_or_else(|_| now.format("%:z").to_string());
+    let instructions = env
+        .mcp
+        .instructions()
+        .map(|(server, instructions)| format!("- {server}: {instructions}"))
+        .collect::<Vec<String>>()
+        .join("\n");
+    let variables = [
+        ("date", now.format("%Y-%m-%d").to_string()),
+        ("time", now.format("%H:%M").to_string()),
+        ("timezone", timezone),
+        (
+            "hostname",
+            gethostname::gethostname().to_string_lossy().into(),
+        ),
+        (
+            "servers",
+            env.mcp.server_names().collect::<Vec<&str>>().join(", "),
+        ),
+        ("instructions", instructions.clone()),
+    ];
+    let mut prompt = render(&template, &variables);
+    // The model should know what the servers are for even if the template doesn't say where
+    // to put the instructions
+    if !instructions.is_empty() && !template.contains("{instructions}") {
+        prompt.push_str(&format!(
+            "\n\nThe tools come from MCP servers, their names start with the server name and '{}'. \
+             The servers describe themselves as follows:\n{instructions}",
+            env.conf.tools.separator
+        ));
+    }
+    Ok(prompt)
+}
+
+// Replaces `{name}` with the value of the variable. Anything else in braces is left as it is,
+// so that the prompt can contain e.g. JSON examples
+fn render(template: &str, variables: &[(&str, String)]) -> String {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            variables
+                .iter()
+                .find(|(name, _)| *name == &rest[1..end])
+                .map(|(_, value)| (end, value))
+        });
+        match value {
+            Some((end, value)) => {
+                rendered.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let variables = [
+            ("date", String::from("2025-05-01")),
+            ("servers", String::from("{date}")),
+        ];
+        assert_eq!(
+            "Today is 2025-05-01 and the servers are {date}. {\"unknown\": {nope}}",
+            render(
+                "Today is {date} and the servers are {servers}. {\"unknown\": {nope}}",
+                &variables
+            )
+        );
+    }
+}
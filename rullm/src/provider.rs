@@ -0,0 +1,108 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestMessage, ChatCompletionTool,
+    CompletionUsage,
+};
+use async_trait::async_trait;
+use futures::StreamExt as _;
+use reqwest_eventsource::{Event, EventSource};
+
+use crate::{
+    anthropic::AnthropicClient,
+    conf::{LLMConfig, LLMProvider, Sampling},
+    mcp::MCP,
+    ollama::OllamaClient,
+    openai::OpenAIClient,
+    retry::Failure,
+};
+
+// The assistant's answer to a single request, regardless of whether it was streamed
+#[derive(Debug, Default)]
+pub struct Reply {
+    pub content: String,
+    pub tool_calls: Vec<ChatCompletionMessageToolCall>,
+    // Not every server reports it
+    pub usage: Option<CompletionUsage>,
+}
+
+// An LLM API that the chat loop talks to. The history is kept in the format of the OpenAI chat
+// completions API, the other providers translate it for their requests
+#[async_trait(?Send)]
+pub trait Provider {
+    fn model(&self) -> &str;
+
+    fn set_model(&mut self, model: &str);
+
+    fn tools(&self) -> &[ChatCompletionTool];
+
+    // The sampling settings from the configuration
+    fn sampling(&self) -> &Sampling;
+
+    // Text is handed to `on_token` as soon as it arrives. Without streaming that is all of it
+    // at once. The lifetime is spelled out, async_trait would otherwise tie it to the call
+    async fn chat(
+        &self,
+        messages: &[ChatCompletionRequestMessage],
+        // Take precedence over the settings from the configuration
+        overrides: &Sampling,
+        on_token: &mut dyn for<'t> FnMut(&'t str),
+    ) -> anyhow::Result<Reply>;
+
+    // A plain answer without tools or streaming, for the requests rullm makes on its own
+    async fn complete(&self, messages: &[ChatCompletionRequestMessage]) -> anyhow::Result<Reply>;
+}
+
+pub fn build(conf: &LLMConfig, mcp: &MCP) -> anyhow::Result<Box<dyn Provider>> {
+    let tools = mcp.list_tools()?;
+    let provider: Box<dyn Provider> = match conf.provider.unwrap_or_default() {
+        LLMProvider::OpenAI => Box::new(OpenAIClient::build(conf, tools)),
+        LLMProvider::Anthropic => Box::new(AnthropicClient::build(conf, tools)),
+        LLMProvider::Ollama => Box::new(OllamaClient::build(conf, tools)),
+    };
+    Ok(provider)
+}
+
+// The data of the next server-sent event, None once the stream has ended. Only the failures
+// before anything was `received` can be retried, after that a new attempt would repeat what
+// was already shown
+pub async fn next_event(
+    events: &mut EventSource,
+    timeout: Duration,
+    received: bool,
+) -> Result<Option<String>, Failure> {
+    loop {
+        let Ok(event) = tokio::time::timeout(timeout, events.next()).await else {
+            return Err(timed_out(timeout, received));
+        };
+        match event {
+            None | Some(Err(reqwest_eventsource::Error::StreamEnded)) => return Ok(None),
+            Some(Ok(Event::Open)) => continue,
+            Some(Ok(Event::Message(message))) => return Ok(Some(message.data)),
+            Some(Err(reqwest_eventsource::Error::InvalidStatusCode(_, response))) => {
+                return Err(Failure::from_response(response).await);
+            }
+            Some(Err(reqwest_eventsource::Error::Transport(error))) if !received => {
+                return Err(Failure::from_reqwest(error));
+            }
+            Some(Err(error)) => return Err(error.into()),
+        }
+    }
+}
+
+pub fn timed_out(timeout: Duration, received: bool) -> Failure {
+    let error = anyhow!("The model didn't answer in {} seconds", timeout.as_secs());
+    interrupted(error, received)
+}
+
+// The answer stopped coming, which is worth another try only if nothing of it was shown yet
+pub fn interrupted(error: anyhow::Error, received: bool) -> Failure {
+    match received {
+        true => Failure::Permanent(error),
+        false => Failure::Transient {
+            error,
+            retry_after: None,
+        },
+    }
+}
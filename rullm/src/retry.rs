@@ -0,0 +1,223 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rand::Rng as _;
+use reqwest::{
+    Response, StatusCode,
+    header::{HeaderMap, RETRY_AFTER},
+};
+use rmcp::serde_json::{self, Value};
+use tracing::warn;
+
+use crate::conf::RetryConfig;
+
+// A failed request. Rate limits, server errors and connection problems are transient, the same
+// request may well succeed later
+#[derive(Debug)]
+pub enum Failure {
+    Transient {
+        error: anyhow::Error,
+        // How long the server asked to wait with the `Retry-After` header
+        retry_after: Option<Duration>,
+    },
+    Permanent(anyhow::Error),
+}
+
+impl<E: std::error::Error + Send + Sync + 'static> From<E> for Failure {
+    fn from(error: E) -> Self {
+        Failure::Permanent(error.into())
+    }
+}
+
+impl Failure {
+    // The request didn't get through, e.g. the connection was refused or it timed out
+    pub fn from_reqwest(error: reqwest::Error) -> Failure {
+        if error.is_connect() || error.is_timeout() || error.is_request() {
+            Failure::Transient {
+                error: error.into(),
+                retry_after: None,
+            }
+        } else {
+            Failure::Permanent(error.into())
+        }
+    }
+
+    // For a response that isn't a success. The message from the body of the response is kept
+    // for the error
+    pub async fn from_response(response: Response) -> Failure {
+        let status = response.status();
+        let retry_after = retry_after(response.headers());
+        let body = response.text().await.unwrap_or_default();
+        let message = serde_json::from_str::<Value>(&body)
+            .ok()
+            .and_then(|x| {
+                let error = x.get("error")?;
+                // Some servers put just a string in `error`
+                let message = error.get("message").unwrap_or(error);
+                Some(message.as_str()?.to_string())
+            })
+            .unwrap_or(body);
+        // OpenAI uses 429 also when the quota is used up, waiting won't help with that
+        let transient = (status == StatusCode::TOO_MANY_REQUESTS && !message.contains("quota"))
+            || status.is_server_error();
+        let error = anyhow::Error::new(HttpError { status, message });
+        if transient {
+            Failure::Transient { error, retry_after }
+        } else {
+            Failure::Permanent(error)
+        }
+    }
+}
+
+// The status and the message of a response that isn't a success, for telling the errors apart
+// where they are handled
+#[derive(Debug)]
+pub struct HttpError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+// Either a number of seconds or a date
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now())
+        .to_std()
+        .ok()
+        .or(Some(Duration::ZERO))
+}
+
+// Makes the request with `attempt` until it succeeds, the failure is permanent or there are no
+// attempts left
+pub async fn retry<T>(
+    conf: &RetryConfig,
+    mut attempt: impl AsyncFnMut() -> Result<T, Failure>,
+) -> anyhow::Result<T> {
+    let mut backoff = Backoff::new(conf);
+    loop {
+        match attempt().await {
+            Ok(value) => return Ok(value),
+            Err(failure) => backoff.wait(failure).await?,
+        }
+    }
+}
+
+// Keeps count of the attempts of a single request
+struct Backoff<'a> {
+    conf: &'a RetryConfig,
+    attempt: u32,
+}
+
+impl<'a> Backoff<'a> {
+    fn new(conf: &'a RetryConfig) -> Backoff<'a> {
+        Backoff { conf, attempt: 1 }
+    }
+
+    // Waits before the next attempt. The error is given back when it's permanent or when there
+    // are no attempts left
+    async fn wait(&mut self, failure: Failure) -> anyhow::Result<()> {
+        let (error, retry_after) = match failure {
+            Failure::Permanent(error) => return Err(error),
+            Failure::Transient { error, retry_after } => (error, retry_after),
+        };
+        if self.attempt >= self.conf.attempts {
+            if self.attempt == 1 {
+                return Err(error);
+            }
+            return Err(error.context(format!("Gave up after {} attempts", self.attempt)));
+        }
+        let max_delay = Duration::from_millis(self.conf.max_delay_ms);
+        // Retrying any sooner would only be turned down again
+        if let Some(retry_after) = retry_after
+            && retry_after > max_delay
+        {
+            return Err(error.context(format!(
+                "The server asked to wait {} seconds, more than the {} allowed by max_delay_ms",
+                retry_after.as_secs(),
+                max_delay.as_secs()
+            )));
+        }
+        let delay = retry_after.unwrap_or_else(|| self.delay());
+        warn!(
+            attempt = self.attempt,
+            delay_ms = delay.as_millis() as u64,
+            "Request failed, retrying: {error:#}"
+        );
+        tokio::time::sleep(delay).await;
+        self.attempt += 1;
+        Ok(())
+    }
+
+    // Doubles with every attempt. Half of it is random, so that clients that failed at the same
+    // time don't all come back at the same time
+    fn delay(&self) -> Duration {
+        let exponent = (self.attempt - 1).min(16);
+        let cap = self
+            .conf
+            .initial_delay_ms
+            .saturating_mul(1 << exponent)
+            .min(self.conf.max_delay_ms);
+        Duration::from_millis(rand::thread_rng().gen_range(cap / 2..=cap))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, retry_after(&headers));
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("7"));
+        assert_eq!(Some(Duration::from_secs(7)), retry_after(&headers));
+        // A date in the past means that it's fine to go again right away
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        assert_eq!(Some(Duration::ZERO), retry_after(&headers));
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("soon"));
+        assert_eq!(None, retry_after(&headers));
+    }
+
+    #[test]
+    fn test_delay_grows_up_to_the_maximum() {
+        let conf = RetryConfig {
+            attempts: 10,
+            initial_delay_ms: 100,
+            max_delay_ms: 1000,
+        };
+        let mut backoff = Backoff::new(&conf);
+        for (attempt, cap) in [(1, 100), (2, 200), (3, 400), (4, 800), (5, 1000), (9, 1000)] {
+            backoff.attempt = attempt;
+            let delay = backoff.delay().as_millis() as u64;
+            assert!(cap / 2 <= delay && delay <= cap, "{attempt}: {delay}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_on_a_long_retry_after() {
+        let conf = RetryConfig::default();
+        let mut backoff = Backoff::new(&conf);
+        let failure = Failure::Transient {
+            error: anyhow!("429 Too Many Requests"),
+            retry_after: Some(Duration::from_secs(3600)),
+        };
+        let error = backoff.wait(failure).await.unwrap_err();
+        assert!(format!("{error}").contains("wait 3600 seconds"), "{error}");
+    }
+}
@@ -0,0 +1,200 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::{Context as _, anyhow};
+use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionRequestUserMessageContent};
+use chrono::{DateTime, Utc};
+use rmcp::serde_json;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    args::Args,
+    conf::LLMProvider,
+    message::{is_turn_start, message_text},
+    usage::Usage,
+};
+
+// A conversation, stored as `<data dir>/rullm/sessions/<id>.json`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Session {
+    pub id: String,
+    pub model: String,
+    // The profile and the provider the session was using, to go back to them on resume. Not in
+    // the sessions saved before they were added
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub provider: Option<LLMProvider>,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+    pub messages: Vec<ChatCompletionRequestMessage>,
+    // The tokens used so far, for /usage after a resume
+    #[serde(default)]
+    pub usage: Usage,
+    // The last message that failed to get an answer, for /retry
+    #[serde(skip)]
+    pub failed: Option<String>,
+}
+
+impl Session {
+    pub fn new(model: &str, profile: Option<&str>, provider: LLMProvider) -> Session {
+        let now = Utc::now();
+        Session {
+            // With the milliseconds, so that a `/clear` right after the session was started doesn't
+            // overwrite it
+            id: now.format("%Y%m%d-%H%M%S-%3f").to_string(),
+            model: String::from(model),
+            profile: profile.map(String::from),
+            provider: Some(provider),
+            created: now,
+            updated: now,
+            messages: vec![],
+            usage: Usage::default(),
+            failed: None,
+        }
+    }
+
+    // The message to send again for /retry. A failed message was already removed from the
+    // history, otherwise everything from the last user message onwards is replaced by the new
+    // answer
+    pub fn take_retry(&mut self) -> anyhow::Result<String> {
+        if let Some(line) = self.failed.take() {
+            return Ok(line);
+        }
+        let last = self
+            .messages
+            .iter()
+            .rposition(is_turn_start)
+            .ok_or(anyhow!("There is nothing to retry"))?;
+        let line = message_text(&self.messages[last]);
+        self.messages.truncate(last);
+        Ok(line)
+    }
+
+    // The session requested with `--resume` or `--continue`, if any
+    pub fn from_args(args: &Args) -> anyhow::Result<Option<Session>> {
+        if let Some(id) = &args.resume {
+            Ok(Some(Session::load(id)?))
+        } else if args.continue_session {
+            let latest = Session::list()?.into_iter().next();
+            Ok(Some(latest.ok_or(anyhow!("There are no saved sessions"))?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn load(id: &str) -> anyhow::Result<Session> {
+        let path = sessions_dir()?.join(format!("{id}.json"));
+        let content =
+            fs::read_to_string(&path).with_context(|| format!("No session with id '{id}'"))?;
+        let session = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        Ok(session)
+    }
+
+    // The usage is counted in `Env`, it's saved together with the messages
+    pub fn save(&mut self, usage: &Usage) -> anyhow::Result<()> {
+        let dir = sessions_dir()?;
+        fs::create_dir_all(&dir)?;
+        self.updated = Utc::now();
+        self.usage = usage.clone();
+        // Write to a temporary file first, so that a crash can't leave a half written session
+        let tmp = dir.join(format!("{}.json.tmp", self.id));
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp, dir.join(format!("{}.json", self.id)))?;
+        Ok(())
+    }
+
+    // All the saved sessions, most recently updated first
+    pub fn list() -> anyhow::Result<Vec<Session>> {
+        let dir = sessions_dir()?;
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut sessions = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|x| x == "json") {
+                let session = fs::read_to_string(&path)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|x| Ok(serde_json::from_str::<Session>(&x)?));
+                match session {
+                    Ok(session) => sessions.push(session),
+                    Err(err) => tracing::warn!(path = %path.display(), "Skipping session: {err}"),
+                }
+            }
+        }
+        sessions.sort_by_key(|x| std::cmp::Reverse(x.updated));
+        Ok(sessions)
+    }
+
+    // The beginning of the first user message, to recognize the session by
+    pub fn title(&self) -> String {
+        let first = self.messages.iter().find_map(|message| match message {
+            ChatCompletionRequestMessage::User(user) => match &user.content {
+                ChatCompletionRequestUserMessageContent::Text(text) => Some(text.as_str()),
+                _ => None,
+            },
+            _ => None,
+        });
+        let title: String = first.unwrap_or("").chars().take(60).collect();
+        title.replace('\n', " ")
+    }
+}
+
+pub fn print_sessions() -> anyhow::Result<()> {
+    for session in Session::list()? {
+        println!(
+            "{}  {}  {}  {}",
+            session.id,
+            session.updated.format("%Y-%m-%d %H:%M"),
+            session.model,
+            session.title()
+        );
+    }
+    Ok(())
+}
+
+fn sessions_dir() -> anyhow::Result<PathBuf> {
+    let mut dir = dirs_next::data_dir().ok_or(anyhow!("Data directory missing"))?;
+    dir.push("rullm");
+    dir.push("sessions");
+    Ok(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::{
+        ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestUserMessageArgs,
+    };
+
+    fn turn(session: &mut Session, question: &str) {
+        session.messages.push(
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(question)
+                .build()
+                .unwrap()
+                .into(),
+        );
+        session.messages.push(
+            ChatCompletionRequestAssistantMessageArgs::default()
+                .content("answer")
+                .build()
+                .unwrap()
+                .into(),
+        );
+    }
+
+    #[test]
+    fn test_retry_failed_message_or_last_turn() {
+        let mut session = Session::new("gpt-4o", None, LLMProvider::OpenAI);
+        assert!(session.take_retry().is_err());
+        turn(&mut session, "A");
+        session.failed = Some(String::from("B"));
+        assert_eq!("B", session.take_retry().unwrap());
+        assert_eq!(2, session.messages.len());
+        // With nothing failed the last turn is done again
+        assert_eq!("A", session.take_retry().unwrap());
+        assert!(session.messages.is_empty());
+    }
+}
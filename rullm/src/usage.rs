@@ -0,0 +1,146 @@
+use std::collections::{BTreeMap, HashMap};
+
+use async_openai::types::CompletionUsage;
+use serde::{Deserialize, Serialize};
+
+use crate::conf::Price;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub struct Tokens {
+    pub requests: u32,
+    pub prompt: u64,
+    pub completion: u64,
+}
+
+impl Tokens {
+    fn add(&mut self, usage: Option<&CompletionUsage>) {
+        self.requests += 1;
+        if let Some(usage) = usage {
+            self.prompt += u64::from(usage.prompt_tokens);
+            self.completion += u64::from(usage.completion_tokens);
+        }
+    }
+
+    fn cost(&self, price: &Price) -> f64 {
+        (self.prompt as f64 * price.prompt + self.completion as f64 * price.completion) / 1e6
+    }
+}
+
+// The tokens used in a session, by model since the model can be switched
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Usage {
+    last: Option<(String, Tokens)>,
+    models: BTreeMap<String, Tokens>,
+}
+
+impl Usage {
+    pub fn record(&mut self, model: &str, usage: Option<&CompletionUsage>) {
+        let mut last = Tokens::default();
+        last.add(usage);
+        self.last = Some((String::from(model), last));
+        self.models
+            .entry(String::from(model))
+            .or_default()
+            .add(usage);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.models.is_empty()
+    }
+
+    pub fn print(&self, prices: &HashMap<String, Price>) {
+        for line in self.report(prices) {
+            println!("{line}");
+        }
+    }
+
+    // The cost is only shown for the models that have a price in the configuration
+    fn report(&self, prices: &HashMap<String, Price>) -> Vec<String> {
+        let mut lines = vec![];
+        let describe = |model: &str, tokens: &Tokens| {
+            let cost = prices.get(model).map(|x| tokens.cost(x));
+            let text = format!(
+                "{} prompt and {} completion tokens{}",
+                tokens.prompt,
+                tokens.completion,
+                cost.map(|x| format!(", cost {x:.4}")).unwrap_or_default()
+            );
+            (text, cost)
+        };
+        if let Some((model, tokens)) = &self.last {
+            lines.push(format!("Last request: {}", describe(model, tokens).0));
+        }
+        let mut total = Tokens::default();
+        let mut total_cost = Some(0.0);
+        for (model, tokens) in &self.models {
+            let (text, cost) = describe(model, tokens);
+            lines.push(format!("{model}: {} requests, {text}", tokens.requests));
+            total.requests += tokens.requests;
+            total.prompt += tokens.prompt;
+            total.completion += tokens.completion;
+            // A partial sum would look like the whole cost
+            total_cost = total_cost.zip(cost).map(|(a, b)| a + b);
+        }
+        if self.models.len() > 1 {
+            let cost = total_cost
+                .map(|x| format!(", cost {x:.4}"))
+                .unwrap_or_default();
+            lines.push(format!(
+                "Total: {} requests, {} prompt and {} completion tokens{cost}",
+                total.requests, total.prompt, total.completion
+            ));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reported(prompt_tokens: u32, completion_tokens: u32) -> CompletionUsage {
+        CompletionUsage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            prompt_tokens_details: None,
+            completion_tokens_details: None,
+        }
+    }
+
+    #[test]
+    fn test_cost_only_with_every_price() {
+        let mut usage = Usage::default();
+        usage.record("gpt-4o", Some(&reported(1_000_000, 100_000)));
+        usage.record("local", Some(&reported(500, 50)));
+        usage.record("gpt-4o", None);
+        let mut prices = HashMap::from([(
+            String::from("gpt-4o"),
+            Price {
+                prompt: 2.5,
+                completion: 10.0,
+            },
+        )]);
+        // A partial price table gives no total cost
+        assert_eq!(
+            vec![
+                "Last request: 0 prompt and 0 completion tokens, cost 0.0000",
+                "gpt-4o: 2 requests, 1000000 prompt and 100000 completion tokens, cost 3.5000",
+                "local: 1 requests, 500 prompt and 50 completion tokens",
+                "Total: 3 requests, 1000500 prompt and 100050 completion tokens",
+            ],
+            usage.report(&prices)
+        );
+        prices.insert(
+            String::from("local"),
+            Price {
+                prompt: 0.0,
+                completion: 0.0,
+            },
+        );
+        assert_eq!(
+            "Total: 3 requests, 1000500 prompt and 100050 completion tokens, cost 3.5000",
+            usage.report(&prices)[3]
+        );
+    }
+}